@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS ratchet_sessions (
+    id BLOB PRIMARY KEY,
+    state BLOB NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1,5 @@
+ALTER TABLE inventory ADD COLUMN size INTEGER;
+ALTER TABLE inventory ADD COLUMN surplus REAL;
+UPDATE inventory SET size = length(payload);
+CREATE INDEX inventory_size ON inventory (size);
+CREATE INDEX inventory_surplus ON inventory (surplus)
\ No newline at end of file
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS inventory_expiration_time ON inventory (expiration_time, blake2b)
\ No newline at end of file
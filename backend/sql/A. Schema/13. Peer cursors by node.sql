@@ -0,0 +1,7 @@
+DROP TABLE IF EXISTS peer_cursors;
+CREATE TABLE peer_cursors (
+    public_key BLOB PRIMARY KEY,
+    their_cursor INTEGER NOT NULL,
+    our_cursor INTEGER NOT NULL,
+    reconciled_at INTEGER NOT NULL
+)
\ No newline at end of file
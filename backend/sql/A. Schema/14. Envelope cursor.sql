@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS envelope_cursor (
+    value INTEGER NOT NULL
+);
+INSERT INTO envelope_cursor SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM envelope_cursor)
\ No newline at end of file
@@ -0,0 +1,10 @@
+ALTER TABLE peers ADD COLUMN shared INTEGER NOT NULL DEFAULT 0;
+CREATE TABLE IF NOT EXISTS learned_peers (
+    address TEXT PRIMARY KEY,
+    source TEXT NOT NULL,
+    last_seen INTEGER NOT NULL,
+    last_success INTEGER,
+    last_failure INTEGER,
+    failure_count INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS learned_peers_source ON learned_peers (source)
\ No newline at end of file
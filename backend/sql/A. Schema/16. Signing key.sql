@@ -0,0 +1,3 @@
+CREATE TABLE IF NOT EXISTS signing_key (
+    seed BLOB NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE envelope_cursor ADD COLUMN unsequenced_scanned INTEGER NOT NULL DEFAULT 0
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS accepted_prekeys (
+    prekey BLOB NOT NULL,
+    ratchet_key BLOB NOT NULL,
+    session_id BLOB NOT NULL,
+    PRIMARY KEY (prekey, ratchet_key)
+)
\ No newline at end of file
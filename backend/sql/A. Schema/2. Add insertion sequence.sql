@@ -0,0 +1 @@
+ALTER TABLE inventory ADD COLUMN sequence INTEGER
\ No newline at end of file
@@ -0,0 +1,3 @@
+CREATE TABLE IF NOT EXISTS insertion_counter (
+    value INTEGER NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1 @@
+INSERT INTO insertion_counter SELECT (SELECT IFNULL(MAX(sequence), 0) FROM inventory) WHERE NOT EXISTS (SELECT 1 FROM insertion_counter)
\ No newline at end of file
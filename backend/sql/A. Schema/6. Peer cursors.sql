@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS peer_cursors (
+    address TEXT PRIMARY KEY,
+    cursor INTEGER
+)
\ No newline at end of file
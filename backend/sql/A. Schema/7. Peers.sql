@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS peers (
+    address TEXT PRIMARY KEY,
+    last_success INTEGER,
+    last_failure INTEGER,
+    failure_count INTEGER NOT NULL DEFAULT 0
+)
\ No newline at end of file
@@ -0,0 +1,3 @@
+CREATE TABLE IF NOT EXISTS identity (
+    seed BLOB NOT NULL
+)
\ No newline at end of file
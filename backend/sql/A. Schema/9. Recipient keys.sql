@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS recipient_keys (
+    public_key BLOB PRIMARY KEY,
+    private_key BLOB NOT NULL
+)
\ No newline at end of file
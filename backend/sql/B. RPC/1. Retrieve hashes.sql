@@ -1 +1 @@
-SELECT blake2b FROM inventory WHERE datetime(expiration_time, 'unixepoch') > datetime('now')
+SELECT blake2b FROM inventory WHERE expiration_time > ?
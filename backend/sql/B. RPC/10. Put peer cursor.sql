@@ -0,0 +1 @@
+INSERT OR REPLACE INTO peer_cursors VALUES (?, ?, ?, ?)
\ No newline at end of file
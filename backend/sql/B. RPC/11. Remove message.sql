@@ -0,0 +1 @@
+DELETE FROM inventory WHERE blake2b = ?
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT MIN(expiration_time) FROM inventory
\ No newline at end of file
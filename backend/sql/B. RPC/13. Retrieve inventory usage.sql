@@ -0,0 +1 @@
+SELECT COUNT(*), IFNULL(SUM(size), 0) FROM inventory
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT blake2b, size FROM inventory WHERE expiration_time < ? ORDER BY expiration_time
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT blake2b, size FROM inventory WHERE size > ? ORDER BY size DESC
\ No newline at end of file
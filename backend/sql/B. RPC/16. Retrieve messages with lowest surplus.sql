@@ -0,0 +1 @@
+SELECT blake2b, size FROM inventory WHERE IFNULL(surplus, 0) < ? ORDER BY surplus
\ No newline at end of file
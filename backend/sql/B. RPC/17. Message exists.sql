@@ -0,0 +1 @@
+SELECT 1 FROM inventory WHERE blake2b = ?
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT blake2b FROM inventory WHERE sequence IS NULL AND expiration_time > ?
\ No newline at end of file
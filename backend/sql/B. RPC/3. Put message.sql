@@ -1 +1 @@
-INSERT OR IGNORE INTO inventory VALUES (?, ?, ?, ?)
+INSERT OR IGNORE INTO inventory (blake2b, payload, nonce, expiration_time, sequence, size, surplus) VALUES (?, ?, ?, ?, (SELECT value FROM insertion_counter), ?, ?)
\ No newline at end of file
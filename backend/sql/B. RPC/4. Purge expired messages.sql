@@ -1 +0,0 @@
-DELETE FROM inventory WHERE datetime(expiration_time, 'unixepoch') <= datetime('now')
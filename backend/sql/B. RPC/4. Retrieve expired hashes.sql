@@ -0,0 +1 @@
+SELECT blake2b FROM inventory WHERE expiration_time <= ?
\ No newline at end of file
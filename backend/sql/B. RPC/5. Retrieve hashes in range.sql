@@ -0,0 +1 @@
+SELECT blake2b FROM inventory WHERE blake2b >= ? AND blake2b < ? AND expiration_time > ? ORDER BY blake2b
@@ -0,0 +1 @@
+SELECT blake2b FROM inventory WHERE sequence > ? AND sequence <= ? AND expiration_time > ? ORDER BY sequence
@@ -0,0 +1 @@
+SELECT value FROM insertion_counter
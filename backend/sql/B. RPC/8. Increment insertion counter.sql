@@ -0,0 +1 @@
+UPDATE insertion_counter SET value = value + 1
@@ -0,0 +1 @@
+SELECT their_cursor, our_cursor, reconciled_at FROM peer_cursors WHERE public_key = ?
\ No newline at end of file
@@ -0,0 +1 @@
+INSERT OR IGNORE INTO peers (address, shared) VALUES (?, ?)
\ No newline at end of file
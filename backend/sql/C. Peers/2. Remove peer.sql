@@ -0,0 +1 @@
+DELETE FROM peers WHERE address = ?
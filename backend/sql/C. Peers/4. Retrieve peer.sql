@@ -0,0 +1 @@
+SELECT address, last_success, last_failure, failure_count, shared FROM peers WHERE address = ?
\ No newline at end of file
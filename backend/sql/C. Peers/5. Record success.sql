@@ -0,0 +1 @@
+UPDATE peers SET last_success = strftime('%s', 'now'), failure_count = 0 WHERE address = ?
@@ -0,0 +1 @@
+SELECT address FROM (SELECT address FROM peers WHERE shared AND last_success IS NOT NULL UNION SELECT address FROM learned_peers WHERE last_success IS NOT NULL) ORDER BY RANDOM() LIMIT ?
\ No newline at end of file
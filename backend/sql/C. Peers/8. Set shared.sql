@@ -0,0 +1 @@
+UPDATE peers SET shared = ? WHERE address = ?
\ No newline at end of file
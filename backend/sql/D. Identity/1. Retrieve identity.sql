@@ -0,0 +1 @@
+SELECT seed FROM identity LIMIT 1
\ No newline at end of file
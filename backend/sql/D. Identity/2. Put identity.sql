@@ -0,0 +1 @@
+INSERT INTO identity VALUES (?)
\ No newline at end of file
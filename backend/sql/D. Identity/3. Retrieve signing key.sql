@@ -0,0 +1 @@
+SELECT seed FROM signing_key LIMIT 1
\ No newline at end of file
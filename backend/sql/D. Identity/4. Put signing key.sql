@@ -0,0 +1 @@
+INSERT INTO signing_key VALUES (?)
\ No newline at end of file
@@ -0,0 +1 @@
+INSERT INTO recipient_keys VALUES (?, ?)
\ No newline at end of file
@@ -0,0 +1 @@
+DELETE FROM recipient_keys WHERE public_key = ?
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT public_key, private_key FROM recipient_keys
\ No newline at end of file
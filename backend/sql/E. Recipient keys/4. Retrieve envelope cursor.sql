@@ -0,0 +1 @@
+SELECT value FROM envelope_cursor
\ No newline at end of file
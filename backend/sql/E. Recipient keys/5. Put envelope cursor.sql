@@ -0,0 +1 @@
+UPDATE envelope_cursor SET value = ?
\ No newline at end of file
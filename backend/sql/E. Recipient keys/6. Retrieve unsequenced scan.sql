@@ -0,0 +1 @@
+SELECT unsequenced_scanned FROM envelope_cursor
\ No newline at end of file
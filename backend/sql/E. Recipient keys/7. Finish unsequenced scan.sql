@@ -0,0 +1 @@
+UPDATE envelope_cursor SET unsequenced_scanned = 1
\ No newline at end of file
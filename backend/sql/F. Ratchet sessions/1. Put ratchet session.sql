@@ -0,0 +1 @@
+INSERT OR REPLACE INTO ratchet_sessions VALUES (?, ?)
\ No newline at end of file
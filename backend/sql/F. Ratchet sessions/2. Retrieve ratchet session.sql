@@ -0,0 +1 @@
+SELECT state FROM ratchet_sessions WHERE id = ?
\ No newline at end of file
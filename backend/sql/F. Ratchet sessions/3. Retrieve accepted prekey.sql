@@ -0,0 +1 @@
+SELECT session_id FROM accepted_prekeys WHERE prekey = ? AND ratchet_key = ?
\ No newline at end of file
@@ -0,0 +1 @@
+DELETE FROM ratchet_sessions WHERE id = ?
\ No newline at end of file
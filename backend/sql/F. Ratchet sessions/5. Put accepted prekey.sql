@@ -0,0 +1 @@
+INSERT OR IGNORE INTO accepted_prekeys VALUES (?, ?, ?)
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT COUNT(*) FROM pragma_table_info('inventory') WHERE name = 'sequence'
\ No newline at end of file
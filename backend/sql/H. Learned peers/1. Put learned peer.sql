@@ -0,0 +1 @@
+INSERT OR IGNORE INTO learned_peers (address, source, last_seen) VALUES (?, ?, strftime('%s', 'now'))
\ No newline at end of file
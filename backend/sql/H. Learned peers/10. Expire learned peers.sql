@@ -0,0 +1 @@
+DELETE FROM learned_peers WHERE last_seen < strftime('%s', 'now') - ? OR failure_count >= ?
\ No newline at end of file
@@ -0,0 +1 @@
+UPDATE learned_peers SET last_seen = strftime('%s', 'now') WHERE address = ?
\ No newline at end of file
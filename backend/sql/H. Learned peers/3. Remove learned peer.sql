@@ -0,0 +1 @@
+DELETE FROM learned_peers WHERE address = ?
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT address, source, last_seen, last_success, last_failure, failure_count FROM learned_peers ORDER BY address
\ No newline at end of file
@@ -0,0 +1 @@
+UPDATE learned_peers SET last_success = strftime('%s', 'now'), last_seen = strftime('%s', 'now'), failure_count = 0 WHERE address = ?
\ No newline at end of file
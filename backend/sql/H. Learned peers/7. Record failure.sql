@@ -0,0 +1 @@
+UPDATE learned_peers SET last_failure = strftime('%s', 'now'), failure_count = failure_count + 1 WHERE address = ?
\ No newline at end of file
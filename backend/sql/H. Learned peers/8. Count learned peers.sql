@@ -0,0 +1 @@
+SELECT COUNT(*) FROM learned_peers
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT COUNT(*) FROM learned_peers WHERE source = ?
\ No newline at end of file
@@ -0,0 +1,63 @@
+use crate::die_on_error::die_on_error;
+use crate::reconcile_capnp::inventory_listener as InventoryListener;
+use futures::executor::LocalSpawner;
+use futures::task::LocalSpawn;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Pushes hashes of newly inserted messages to peers that subscribed to our
+/// inventory.
+pub struct Announcer {
+    counter: u128,
+    listeners: HashMap<u128, InventoryListener::Client>,
+    spawner: LocalSpawner,
+}
+
+impl Announcer {
+    pub fn new(spawner: LocalSpawner) -> Announcer {
+        Announcer {
+            counter: 0,
+            listeners: HashMap::new(),
+            spawner,
+        }
+    }
+
+    pub fn subscribe(&mut self, listener: InventoryListener::Client) -> u128 {
+        let subscription = self.counter;
+        self.listeners.insert(subscription, listener);
+        self.counter += 1;
+        subscription
+    }
+
+    pub fn unsubscribe(&mut self, subscription: u128) {
+        self.listeners.remove(&subscription);
+    }
+
+    pub fn announce(&self, hashes: &[Vec<u8>]) {
+        if hashes.is_empty() {
+            return;
+        }
+        for listener in self.listeners.values() {
+            let mut request = listener.announce_request();
+            {
+                let mut list = request
+                    .get()
+                    .init_hashes(die_on_error(hashes.len().try_into()));
+                for (i, hash) in hashes.iter().enumerate() {
+                    list.set(die_on_error(i.try_into()), hash);
+                }
+            }
+            let promise = request.send().promise;
+            die_on_error(
+                self.spawner.spawn_local_obj(
+                    Box::new(async move {
+                        // Listeners that went away are unsubscribed when
+                        // their connection closes.
+                        let _ = promise.await;
+                    })
+                    .into(),
+                ),
+            );
+        }
+    }
+}
@@ -1,76 +1,121 @@
+use crate::announcer::Announcer;
 use crate::die_on_error::die_on_error;
 use crate::log;
 use crate::mpmc_manual_reset_event;
+use crate::protocol::SessionOptions;
 use crate::reconcile_client;
-use crate::reconcile_server;
+use crate::socks5;
 use async_std::sync::RwLock;
+use capnp_rpc::rpc_twoparty_capnp::Side;
 use futures::executor::LocalSpawner;
 use futures::task::LocalSpawn;
 use r2d2_sqlite::SqliteConnectionManager;
+
+/// Dials `address` directly, or through the SOCKS5 proxy at `proxy`.
+async fn dial(address: &str, proxy: Option<&str>) -> std::io::Result<async_std::net::TcpStream> {
+    match proxy {
+        Some(proxy) => socks5::connect(proxy, address).await,
+        None => async_std::net::TcpStream::connect(address).await,
+    }
+}
+
+/// `on_disconnected` is called once a successfully dialed session ends.
+#[allow(clippy::too_many_arguments)]
 pub fn connect<F1, F2>(
     address: String,
+    proxy: Option<String>,
     connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
     handle: LocalSpawner,
     reconciliation_intent: std::rc::Rc<RwLock<mpmc_manual_reset_event::MPMCManualResetEvent>>,
+    announcer: std::rc::Rc<RwLock<Announcer>>,
+    options: std::rc::Rc<SessionOptions>,
     on_connection_failed: F1,
-    on_reconcile_failed: F2,
+    on_disconnected: F2,
 ) where
     F1: FnOnce(std::io::Error) -> () + 'static,
-    F2: FnOnce(capnp::Error) -> () + 'static,
+    F2: FnOnce(Result<(), capnp::Error>) + 'static,
 {
     let handle1 = handle.clone();
     die_on_error(
         handle.spawn_local_obj(
             Box::new(async move {
-                log::notice(format!("Connecting to {}", address));
-                let stream = match async_std::net::TcpStream::connect(&address).await {
+                match &proxy {
+                    Some(proxy) => {
+                        log::notice(format!("Connecting to {} through {}", address, proxy))
+                    }
+                    None => log::notice(format!("Connecting to {}", address)),
+                }
+                let stream = match dial(&address, proxy.as_deref()).await {
                     Ok(stream) => stream,
                     Err(error) => {
                         on_connection_failed(error);
                         return;
                     }
                 };
-                if let Err(error) = reconcile_client::reconcile(
-                    stream,
-                    connection.clone(),
-                    handle1,
-                    reconciliation_intent,
-                )
-                .await
-                {
-                    on_reconcile_failed(error);
-                }
+                on_disconnected(
+                    reconcile_client::reconcile(
+                        stream,
+                        Side::Client,
+                        Some(address),
+                        connection.clone(),
+                        handle1,
+                        reconciliation_intent,
+                        announcer,
+                        options,
+                    )
+                    .await,
+                );
             })
             .into(),
         ),
     );
 }
 
+/// Same as `connect`, except the dialing node takes the server side of the
+/// two-party network.
+#[allow(clippy::too_many_arguments)]
 pub fn reverse_connect<F1, F2>(
     address: String,
+    proxy: Option<String>,
     connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
     handle: LocalSpawner,
     reconciliation_intent: std::rc::Rc<RwLock<mpmc_manual_reset_event::MPMCManualResetEvent>>,
+    announcer: std::rc::Rc<RwLock<Announcer>>,
+    options: std::rc::Rc<SessionOptions>,
     on_connection_failed: F1,
     on_reconcile_failed: F2,
 ) where
     F1: FnOnce(std::io::Error) -> () + 'static,
     F2: FnOnce(capnp::Error) -> () + 'static,
 {
+    let handle1 = handle.clone();
     die_on_error(
         handle.spawn_local_obj(
             Box::new(async move {
-                log::notice(format!("Connecting to {}", address));
-                let stream = match async_std::net::TcpStream::connect(&address).await {
+                match &proxy {
+                    Some(proxy) => {
+                        log::notice(format!("Connecting to {} through {}", address, proxy))
+                    }
+                    None => log::notice(format!("Connecting to {}", address)),
+                }
+                let stream = match dial(&address, proxy.as_deref()).await {
                     Ok(stream) => stream,
                     Err(error) => {
                         on_connection_failed(error);
                         return;
                     }
                 };
-                if let Err(error) =
-                    reconcile_server::init_server(stream, connection.clone(), reconciliation_intent)
-                        .await
+                if let Err(error) = reconcile_client::reconcile(
+                    stream,
+                    Side::Server,
+                    Some(address),
+                    connection.clone(),
+                    handle1,
+                    reconciliation_intent,
+                    announcer,
+                    options,
+                )
+                .await
                 {
                     on_reconcile_failed(error);
                 }
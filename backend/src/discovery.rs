@@ -0,0 +1,163 @@
+use crate::announcer::Announcer;
+use crate::connect::connect;
+use crate::die_on_error::die_on_error;
+use crate::log;
+use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::protocol::SessionOptions;
+use crate::stdio_ipc::{format_struct, Message};
+use async_std::sync::RwLock;
+use futures::executor::LocalSpawner;
+use futures::task::LocalSpawn;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use r2d2_sqlite::SqliteConnectionManager;
+use rand::Rng;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+
+const SERVICE_TYPE: &str = "_contrasleuth._tcp.local.";
+
+fn instance_name(fullname: &str) -> &str {
+    fullname.split('.').next().unwrap_or("")
+}
+
+/// Prefers IPv4, since IPv6 link-local addresses can't be dialed without a
+/// scope.
+fn pick_address(info: &ServiceInfo) -> Option<SocketAddr> {
+    let ip = match info.get_addresses_v4().into_iter().next() {
+        Some(ip) => IpAddr::V4(*ip),
+        None => *info.get_addresses().iter().next()?,
+    };
+    Some(SocketAddr::new(ip, info.get_port()))
+}
+
+/// Advertises the listen address on the local link and dials the nodes
+/// found there.
+pub fn start(
+    listen_address: SocketAddr,
+    connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
+    spawner: LocalSpawner,
+    reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+    announcer: std::rc::Rc<RwLock<Announcer>>,
+    options: std::rc::Rc<SessionOptions>,
+) {
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(error) => {
+            log::warning(format!("mDNS is unavailable due to error {:?}", error));
+            return;
+        }
+    };
+
+    let instance = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let host_name = format!("{}.local.", instance);
+    let service = if listen_address.ip().is_unspecified() {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host_name,
+            (),
+            listen_address.port(),
+            None,
+        )
+        .map(ServiceInfo::enable_addr_auto)
+    } else {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance,
+            &host_name,
+            listen_address.ip(),
+            listen_address.port(),
+            None,
+        )
+    };
+    if let Err(error) = service.and_then(|service| daemon.register(service)) {
+        log::warning(format!(
+            "Can't advertise this node over mDNS due to error {:?}",
+            error
+        ));
+    }
+
+    let receiver = match daemon.browse(SERVICE_TYPE) {
+        Ok(receiver) => receiver,
+        Err(error) => {
+            log::warning(format!(
+                "Can't browse for nodes over mDNS due to error {:?}",
+                error
+            ));
+            return;
+        }
+    };
+    log::notice("Looking for nodes on the local network");
+
+    let spawner1 = spawner.clone();
+    die_on_error(
+        spawner.spawn_local_obj(
+            Box::new(async move {
+                // Dropping the daemon would stop the advertisement.
+                let _daemon = daemon;
+                let mut discovered = HashSet::new();
+                let dialing = std::rc::Rc::new(RefCell::new(HashSet::new()));
+                while let Ok(event) = receiver.recv_async().await {
+                    let info = match event {
+                        ServiceEvent::ServiceResolved(info) => info,
+                        _ => continue,
+                    };
+                    let peer_instance = instance_name(info.get_fullname());
+                    if peer_instance == instance {
+                        continue;
+                    }
+                    let address = match pick_address(&info) {
+                        Some(address) => address.to_string(),
+                        None => continue,
+                    };
+                    if discovered.insert(address.clone()) {
+                        log::notice(format!("Discovered node {} on the local network", address));
+                        log::ipc(format_struct(&Message::PeerDiscovered {
+                            address: &address,
+                        }));
+                    }
+                    // Sessions are symmetric, so only one of the two nodes
+                    // needs to dial.
+                    if instance.as_str() > peer_instance
+                        || !dialing.borrow_mut().insert(address.clone())
+                    {
+                        continue;
+                    }
+                    let dialing1 = dialing.clone();
+                    let dialing2 = dialing.clone();
+                    let address1 = address.clone();
+                    let address2 = address.clone();
+                    // Nodes on the local network are dialed directly, since a
+                    // proxy couldn't reach them.
+                    connect(
+                        address,
+                        None,
+                        connection.clone(),
+                        spawner1.clone(),
+                        reconciliation_intent.clone(),
+                        announcer.clone(),
+                        options.clone(),
+                        move |error| {
+                            log::warning(format!(
+                                "Can't connect to {} due to error {:?}",
+                                address1, error
+                            ));
+                            dialing1.borrow_mut().remove(&address1);
+                        },
+                        move |result| {
+                            if let Err(error) = result {
+                                log::warning(format!(
+                                    "Error occurred while reconciling with {} due to error {:?}",
+                                    address2, error
+                                ));
+                            }
+                            dialing2.borrow_mut().remove(&address2);
+                        },
+                    );
+                }
+            })
+            .into(),
+        ),
+    );
+}
@@ -0,0 +1,280 @@
+use crate::die_on_error::die_on_error;
+use crate::inventory::Inventory;
+use crate::log;
+use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::signed_envelope;
+use crate::stdio_ipc::{format_struct, Message};
+use async_std::sync::RwLock;
+use async_std::task;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::blake2b::Blake2b;
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::digest::Digest;
+use rand::RngCore;
+use rusqlite::params;
+
+type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
+
+// Envelope layout: version, ephemeral public key, recipient count, one
+// wrapped content key per recipient, then the encrypted payload. Recipients
+// aren't named, so each registered key is tried against every slot.
+const VERSION: u8 = 1;
+const KEY_LENGTH: usize = 32;
+const TAG_LENGTH: usize = 16;
+const WRAPPED_KEY_LENGTH: usize = KEY_LENGTH + TAG_LENGTH;
+const HEADER_LENGTH: usize = 1 + KEY_LENGTH + 1;
+const MAX_RECIPIENTS: usize = 255;
+
+/// Every key encrypts exactly one message, so the nonce can be fixed.
+const NONCE: [u8; 8] = [0; 8];
+
+pub struct RecipientKey {
+    pub public_key: Vec<u8>,
+    pub private_key: Vec<u8>,
+}
+
+fn random_key() -> [u8; KEY_LENGTH] {
+    let mut key = [0u8; KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+fn wrapping_key(shared_secret: &[u8], ephemeral_public_key: &[u8], public_key: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake2b::new(KEY_LENGTH);
+    hasher.input(b"contrasleuth envelope");
+    hasher.input(shared_secret);
+    hasher.input(ephemeral_public_key);
+    hasher.input(public_key);
+    let mut key = [0u8; KEY_LENGTH];
+    hasher.result(&mut key);
+    key
+}
+
+fn encrypt(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut output = vec![0u8; plaintext.len() + TAG_LENGTH];
+    let (ciphertext, tag) = output.split_at_mut(plaintext.len());
+    ChaCha20Poly1305::new(key, &NONCE, aad).encrypt(plaintext, ciphertext, tag);
+    output
+}
+
+fn decrypt(key: &[u8], aad: &[u8], input: &[u8]) -> Option<Vec<u8>> {
+    if input.len() < TAG_LENGTH {
+        return None;
+    }
+    let (ciphertext, tag) = input.split_at(input.len() - TAG_LENGTH);
+    let mut plaintext = vec![0u8; ciphertext.len()];
+    if ChaCha20Poly1305::new(key, &NONCE, aad).decrypt(ciphertext, &mut plaintext, tag) {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+/// Returns None if a public key is malformed or there are too many or no
+/// recipients.
+pub fn seal(plaintext: &[u8], recipients: &[Vec<u8>]) -> Option<Vec<u8>> {
+    if recipients.is_empty()
+        || recipients.len() > MAX_RECIPIENTS
+        || recipients.iter().any(|key| key.len() != KEY_LENGTH)
+    {
+        return None;
+    }
+    let ephemeral_private_key = random_key();
+    let ephemeral_public_key = curve25519_base(&ephemeral_private_key);
+    let content_key = random_key();
+
+    let mut envelope = vec![VERSION];
+    envelope.extend_from_slice(&ephemeral_public_key);
+    envelope.push(recipients.len() as u8);
+    for public_key in recipients {
+        let shared_secret = curve25519(&ephemeral_private_key, public_key);
+        let key = wrapping_key(&shared_secret, &ephemeral_public_key, public_key);
+        envelope.extend(encrypt(&key, &[], &content_key));
+    }
+    let body = encrypt(&content_key, &envelope, plaintext);
+    envelope.extend(body);
+    Some(envelope)
+}
+
+/// Returns the plaintext if the envelope is addressed to `key`.
+pub fn open(envelope: &[u8], key: &RecipientKey) -> Option<Vec<u8>> {
+    if envelope.len() < HEADER_LENGTH || envelope[0] != VERSION {
+        return None;
+    }
+    let ephemeral_public_key = &envelope[1..1 + KEY_LENGTH];
+    let header_length = HEADER_LENGTH + envelope[1 + KEY_LENGTH] as usize * WRAPPED_KEY_LENGTH;
+    if envelope.len() < header_length {
+        return None;
+    }
+    let shared_secret = curve25519(&key.private_key, ephemeral_public_key);
+    let key = wrapping_key(&shared_secret, ephemeral_public_key, &key.public_key);
+    envelope[HEADER_LENGTH..header_length]
+        .chunks(WRAPPED_KEY_LENGTH)
+        .find_map(|wrapped| decrypt(&key, &[], wrapped))
+        .and_then(|content_key| {
+            decrypt(
+                &content_key,
+                &envelope[..header_length],
+                &envelope[header_length..],
+            )
+        })
+}
+
+pub fn generate(pool: Pool) -> Vec<u8> {
+    let private_key = random_key();
+    let public_key = curve25519_base(&private_key);
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/E. Recipient keys/1. Put recipient key.sql"),
+        params![public_key.to_vec(), private_key.to_vec()],
+    ));
+    public_key.to_vec()
+}
+
+pub fn remove(pool: Pool, public_key: &[u8]) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/E. Recipient keys/2. Remove recipient key.sql"),
+        params![public_key],
+    ));
+}
+
+pub fn list(pool: Pool) -> Vec<RecipientKey> {
+    let connection = die_on_error(pool.get());
+    let mut statement = die_on_error(connection.prepare(include_str!(
+        "../sql/E. Recipient keys/3. Retrieve recipient keys.sql"
+    )));
+    let mut rows = die_on_error(statement.query(params![]));
+    let mut keys = Vec::new();
+    while let Some(row) = die_on_error(rows.next()) {
+        keys.push(RecipientKey {
+            public_key: die_on_error(row.get(0)),
+            private_key: die_on_error(row.get(1)),
+        });
+    }
+    keys
+}
+
+fn cursor(pool: Pool) -> i64 {
+    die_on_error(die_on_error(pool.get()).query_row(
+        include_str!("../sql/E. Recipient keys/4. Retrieve envelope cursor.sql"),
+        params![],
+        |row| row.get(0),
+    ))
+}
+
+fn set_cursor(pool: Pool, cursor: i64) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/E. Recipient keys/5. Put envelope cursor.sql"),
+        params![cursor],
+    ));
+}
+
+fn unsequenced_scanned(pool: Pool) -> bool {
+    die_on_error(die_on_error(pool.get()).query_row(
+        include_str!("../sql/E. Recipient keys/6. Retrieve unsequenced scan.sql"),
+        params![],
+        |row| row.get(0),
+    ))
+}
+
+fn finish_unsequenced_scan(pool: Pool) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/E. Recipient keys/7. Finish unsequenced scan.sql"),
+        params![],
+    ));
+}
+
+type Received = (Vec<u8>, Vec<u8>, Vec<u8>, crate::inventory::Message);
+
+fn trial_decrypt(
+    keys: &[RecipientKey],
+    inventory: &Inventory,
+    hashes: Vec<Vec<u8>>,
+) -> Vec<Received> {
+    let mut messages = Vec::new();
+    if keys.is_empty() {
+        return messages;
+    }
+    for hash in hashes {
+        let message = match inventory.retrieve(&hash) {
+            Some(message) => message,
+            None => continue,
+        };
+        for key in keys {
+            if let Some(plaintext) = open(&message.payload, key) {
+                messages.push((hash, key.public_key.clone(), plaintext, message));
+                break;
+            }
+        }
+    }
+    messages
+}
+
+fn report(messages: Vec<Received>) {
+    for (hash, recipient, plaintext, message) in messages {
+        log::notice("Received a message addressed to a registered key");
+        let verification = signed_envelope::verify(&plaintext);
+        log::ipc(format_struct(&Message::MessageForYou {
+            hash,
+            recipient,
+            plaintext,
+            verification,
+            expiration_time: message.expiration_time,
+        }));
+    }
+}
+
+/// Trial-decrypts messages as they are inserted and reports those addressed
+/// to a registered key. How far it got is kept across restarts, and only
+/// saved once the messages are reported, so every message is reported at
+/// least once.
+pub async fn watch(
+    connection: Pool,
+    inventory: Inventory,
+    reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+) {
+    let handle = reconciliation_intent.write().await.get_handle();
+    let event = reconciliation_intent.read().await.get_event(handle);
+    let connection1 = connection.clone();
+    let inventory1 = inventory.clone();
+    let (mut cursor, unsequenced) = task::spawn(async move {
+        if unsequenced_scanned(connection1.clone()) {
+            return (cursor(connection1), None);
+        }
+        // Messages stored before insertions were numbered are tried once.
+        let keys = list(connection1.clone());
+        let messages = trial_decrypt(&keys, &inventory1, inventory1.unsequenced_hashes());
+        (cursor(connection1), Some(messages))
+    })
+    .await;
+    if let Some(messages) = unsequenced {
+        report(messages);
+        let connection = connection.clone();
+        task::spawn(async move { finish_unsequenced_scan(connection) }).await;
+    }
+    loop {
+        let connection1 = connection.clone();
+        let inventory = inventory.clone();
+        let (messages, next_cursor) = task::spawn(async move {
+            let keys = list(connection1);
+            let (mut hashes, mut next_cursor) = inventory.hashes_since(cursor);
+            // The sequence starts over with a fresh inventory, such as an
+            // in-memory one after a restart.
+            if next_cursor < cursor {
+                let (all_hashes, cursor) = inventory.hashes_since(0);
+                hashes = all_hashes;
+                next_cursor = cursor;
+            }
+            (trial_decrypt(&keys, &inventory, hashes), next_cursor)
+        })
+        .await;
+        report(messages);
+        if next_cursor != cursor {
+            let connection = connection.clone();
+            task::spawn(async move { set_cursor(connection, next_cursor) }).await;
+        }
+        cursor = next_cursor;
+        event.wait().await;
+        event.reset();
+    }
+}
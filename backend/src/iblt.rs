@@ -0,0 +1,248 @@
+use crypto::blake2b::Blake2b;
+use crypto::digest::Digest;
+
+/// Number of cells each key is added to.
+pub const HASH_COUNT: usize = 3;
+
+/// Sketch size requested by the reconciliation client. A sketch of this size
+/// decodes differences of up to roughly two thirds as many hashes.
+pub const DEFAULT_CELL_COUNT: u32 = 96;
+
+/// Upper bound on the sketch size served to peers.
+pub const MAX_CELL_COUNT: u32 = 4096;
+
+const KEY_LENGTH: usize = 64;
+
+/// Keys only present in one table, and keys only present in the other.
+pub type Difference = (Vec<Vec<u8>>, Vec<Vec<u8>>);
+
+#[derive(Clone)]
+pub struct Cell {
+    pub count: i64,
+    pub key_sum: [u8; KEY_LENGTH],
+    pub hash_sum: u64,
+}
+
+impl Cell {
+    fn empty() -> Cell {
+        Cell {
+            count: 0,
+            key_sum: [0u8; KEY_LENGTH],
+            hash_sum: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.hash_sum == 0 && self.key_sum.iter().all(|byte| *byte == 0)
+    }
+
+    fn is_pure(&self) -> bool {
+        (self.count == 1 || self.count == -1) && self.hash_sum == check_hash(&self.key_sum)
+    }
+
+    fn toggle(&mut self, key: &[u8], hash: u64, count: i64) {
+        self.count = self.count.wrapping_add(count);
+        for (sum, byte) in self.key_sum.iter_mut().zip(key) {
+            *sum ^= byte;
+        }
+        self.hash_sum ^= hash;
+    }
+}
+
+fn check_hash(key: &[u8]) -> u64 {
+    let mut hasher = Blake2b::new(8);
+    hasher.input(key);
+    let mut result = [0u8; 8];
+    hasher.result(&mut result);
+    u64::from_be_bytes(result)
+}
+
+/// Invertible Bloom lookup table over message hashes. Subtracting the peer's
+/// table from ours leaves only the symmetric difference, which can be listed
+/// as long as it is small relative to the number of cells.
+pub struct InvertibleBloomLookupTable {
+    cells: Vec<Cell>,
+}
+
+impl InvertibleBloomLookupTable {
+    pub fn new(cell_count: u32) -> InvertibleBloomLookupTable {
+        // Every hash function gets its own partition, so a key never lands
+        // in the same cell twice.
+        let partition = std::cmp::max(1, cell_count as usize / HASH_COUNT);
+        InvertibleBloomLookupTable {
+            cells: vec![Cell::empty(); partition * HASH_COUNT],
+        }
+    }
+
+    pub fn from_cells(cells: Vec<Cell>) -> Option<InvertibleBloomLookupTable> {
+        if cells.len() < HASH_COUNT {
+            return None;
+        }
+        Some(InvertibleBloomLookupTable { cells })
+    }
+
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    fn indices(&self, key: &[u8; KEY_LENGTH]) -> [usize; HASH_COUNT] {
+        // Keys are BLAKE2b hashes, so their bytes are already uniformly
+        // distributed.
+        let partition = self.cells.len() / HASH_COUNT;
+        let mut indices = [0usize; HASH_COUNT];
+        for (i, index) in indices.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&key[i * 8..i * 8 + 8]);
+            *index = i * partition + (u64::from_be_bytes(bytes) % partition as u64) as usize;
+        }
+        indices
+    }
+
+    fn toggle(&mut self, key: &[u8; KEY_LENGTH], count: i64) {
+        let hash = check_hash(key);
+        for index in self.indices(key).iter() {
+            self.cells[*index].toggle(key, hash, count);
+        }
+    }
+
+    /// Keys that aren't 64 bytes long are ignored.
+    pub fn insert(&mut self, key: &[u8]) {
+        if key.len() != KEY_LENGTH {
+            return;
+        }
+        let mut fixed = [0u8; KEY_LENGTH];
+        fixed.copy_from_slice(key);
+        self.toggle(&fixed, 1);
+    }
+
+    /// Returns the keys only present in `self` and the keys only present in
+    /// `other`, or None if the difference is too large to decode. Both tables
+    /// must have the same number of cells.
+    pub fn difference(mut self, other: &InvertibleBloomLookupTable) -> Option<Difference> {
+        if self.cells.len() != other.cells.len() {
+            return None;
+        }
+        for (cell, other_cell) in self.cells.iter_mut().zip(other.cells.iter()) {
+            cell.toggle(
+                &other_cell.key_sum,
+                other_cell.hash_sum,
+                other_cell.count.wrapping_neg(),
+            );
+        }
+
+        let mut ours = Vec::new();
+        let mut theirs = Vec::new();
+        let mut pure: Vec<usize> = (0..self.cells.len())
+            .filter(|index| self.cells[*index].is_pure())
+            .collect();
+        while let Some(index) = pure.pop() {
+            let cell = &self.cells[index];
+            if !cell.is_pure() {
+                continue;
+            }
+            // A difference larger than the table can only come from a
+            // malformed sketch.
+            if ours.len() + theirs.len() > self.cells.len() {
+                return None;
+            }
+            let key = cell.key_sum;
+            let count = cell.count;
+            if count == 1 {
+                ours.push(key.to_vec());
+            } else {
+                theirs.push(key.to_vec());
+            }
+            self.toggle(&key, -count);
+            for index in self.indices(&key).iter() {
+                if self.cells[*index].is_pure() {
+                    pure.push(*index);
+                }
+            }
+        }
+
+        if self.cells.iter().all(Cell::is_empty) {
+            Some((ours, theirs))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u32) -> Vec<u8> {
+        let mut hasher = Blake2b::new(KEY_LENGTH);
+        hasher.input(&seed.to_be_bytes());
+        let mut result = [0u8; KEY_LENGTH];
+        hasher.result(&mut result);
+        result.to_vec()
+    }
+
+    fn table(keys: impl Iterator<Item = u32>) -> InvertibleBloomLookupTable {
+        let mut table = InvertibleBloomLookupTable::new(DEFAULT_CELL_COUNT);
+        for seed in keys {
+            table.insert(&key(seed));
+        }
+        table
+    }
+
+    fn sorted(mut keys: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn identical_tables_have_no_difference() {
+        let (ours, theirs) = table(0..1000).difference(&table(0..1000)).unwrap();
+        assert!(ours.is_empty());
+        assert!(theirs.is_empty());
+    }
+
+    #[test]
+    fn small_differences_decode() {
+        // Both sides share 1000 keys; each holds 20 the other lacks.
+        let ours = table(0..1020);
+        let theirs = table((0..1000).chain(2000..2020));
+        let (only_ours, only_theirs) = ours.difference(&theirs).unwrap();
+        assert_eq!(sorted(only_ours), sorted((1000..1020).map(key).collect()));
+        assert_eq!(sorted(only_theirs), sorted((2000..2020).map(key).collect()));
+    }
+
+    #[test]
+    fn large_differences_fail_to_decode() {
+        let ours = table(0..1000);
+        let theirs = table(1000..2000);
+        assert!(ours.difference(&theirs).is_none());
+    }
+
+    #[test]
+    fn mismatched_sizes_fail_to_decode() {
+        let ours = InvertibleBloomLookupTable::new(DEFAULT_CELL_COUNT);
+        let theirs = InvertibleBloomLookupTable::new(DEFAULT_CELL_COUNT * 2);
+        assert!(ours.difference(&theirs).is_none());
+    }
+
+    #[test]
+    fn tables_survive_a_round_trip_through_cells() {
+        let ours = table(0..110);
+        let theirs =
+            InvertibleBloomLookupTable::from_cells(table(0..100).cells().to_vec()).unwrap();
+        let (only_ours, only_theirs) = ours.difference(&theirs).unwrap();
+        assert_eq!(sorted(only_ours), sorted((100..110).map(key).collect()));
+        assert!(only_theirs.is_empty());
+    }
+
+    #[test]
+    fn malformed_input_is_ignored_or_rejected() {
+        assert!(
+            InvertibleBloomLookupTable::from_cells(vec![Cell::empty(); HASH_COUNT - 1]).is_none()
+        );
+        let mut ours = table(0..10);
+        ours.insert(&[1, 2, 3]);
+        let (only_ours, only_theirs) = ours.difference(&table(0..10)).unwrap();
+        assert!(only_ours.is_empty());
+        assert!(only_theirs.is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::die_on_error::die_on_error;
+use crypto::digest::Digest;
+use crypto::sha2::Sha512;
+use futures::future::AbortHandle;
+use rand::RngCore;
+use rusqlite::params;
+use std::collections::HashMap;
+
+type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
+
+pub const PUBLIC_KEY_LENGTH: usize = 32;
+pub const SIGNATURE_LENGTH: usize = 64;
+pub const NONCE_LENGTH: usize = 32;
+
+/// Long-term Ed25519 key pair of this node. Only the seed is stored.
+pub struct Identity {
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    secret_key: [u8; 64],
+}
+
+impl Identity {
+    /// Generates the key pair on first start.
+    pub fn load_or_generate(pool: Pool) -> Identity {
+        let seed = load_or_generate_seed(
+            pool,
+            include_str!("../sql/D. Identity/1. Retrieve identity.sql"),
+            include_str!("../sql/D. Identity/2. Put identity.sql"),
+        );
+        let (secret_key, public_key) = crypto::ed25519::keypair(&seed);
+        Identity {
+            public_key,
+            secret_key,
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_LENGTH] {
+        crypto::ed25519::signature(message, &self.secret_key)
+    }
+
+    /// The X25519 counterpart of the Ed25519 key, derived from the seed the
+    /// same way libsodium does.
+    pub fn noise_private_key(&self) -> [u8; 32] {
+        let mut hasher = Sha512::new();
+        hasher.input(&self.secret_key[..32]);
+        let mut hash = [0u8; 64];
+        hasher.result(&mut hash);
+        let mut private_key = [0u8; 32];
+        private_key.copy_from_slice(&hash[..32]);
+        private_key
+    }
+}
+
+/// Returns the seed stored in a single-row table, generating it first if
+/// there is none.
+pub fn load_or_generate_seed(pool: Pool, retrieve: &str, put: &str) -> Vec<u8> {
+    let connection = die_on_error(pool.get());
+    let mut statement = die_on_error(connection.prepare(retrieve));
+    let mut rows = die_on_error(statement.query(params![]));
+    match die_on_error(rows.next()) {
+        Some(row) => die_on_error(row.get(0)),
+        None => {
+            let mut seed = vec![0u8; 32];
+            rand::thread_rng().fill_bytes(&mut seed);
+            die_on_error(connection.execute(put, params![seed]));
+            seed
+        }
+    }
+}
+
+pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    public_key.len() == PUBLIC_KEY_LENGTH
+        && signature.len() == SIGNATURE_LENGTH
+        && crypto::ed25519::verify(message, public_key, signature)
+}
+
+pub fn generate_nonce() -> [u8; NONCE_LENGTH] {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// What the peer signs to prove it holds its key: the nonce we sent, the
+/// nonce it sent and the Noise handshake hash of the connection, if any.
+pub fn challenge(requester_nonce: &[u8], responder_nonce: &[u8], handshake_hash: &[u8]) -> Vec<u8> {
+    [requester_nonce, responder_nonce, handshake_hash].concat()
+}
+
+/// Identifies a connection the same way on both ends.
+pub fn session_id(our_nonce: &[u8], their_nonce: &[u8]) -> Vec<u8> {
+    if our_nonce < their_nonce {
+        [our_nonce, their_nonce].concat()
+    } else {
+        [their_nonce, our_nonce].concat()
+    }
+}
+
+struct ActiveSession {
+    id: Vec<u8>,
+    disconnect: AbortHandle,
+}
+
+/// Keeps at most one session per node. When two sessions to the same node
+/// overlap, the one with the lower session ID survives, so both ends close
+/// the same connection.
+#[derive(Default)]
+pub struct ActiveSessions {
+    sessions: HashMap<Vec<u8>, ActiveSession>,
+}
+
+impl ActiveSessions {
+    /// Returns false if the session duplicates one that takes precedence.
+    pub fn register(&mut self, public_key: &[u8], id: Vec<u8>, disconnect: AbortHandle) -> bool {
+        if let Some(existing) = self.sessions.get(public_key) {
+            if existing.id < id {
+                return false;
+            }
+            existing.disconnect.abort();
+        }
+        self.sessions
+            .insert(public_key.to_vec(), ActiveSession { id, disconnect });
+        true
+    }
+
+    pub fn unregister(&mut self, public_key: &[u8], id: &[u8]) {
+        if let Some(existing) = self.sessions.get(public_key) {
+            if existing.id == id {
+                self.sessions.remove(public_key);
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use crate::log;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+
+/// An address range such as `10.0.0.0/8`. A bare address is a range of one.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+/// IPv4 clients of dual-stack listeners show up as IPv4-mapped IPv6
+/// addresses.
+fn canonical(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => match v6.to_ipv4() {
+            Some(v4) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(v4),
+            _ => address,
+        },
+        IpAddr::V4(_) => address,
+    }
+}
+
+fn bits(address: IpAddr) -> (u128, u8) {
+    match address {
+        IpAddr::V4(v4) => (u128::from(u32::from(v4)), 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Option<Cidr> {
+        let (network, prefix_length) = match value.find('/') {
+            Some(slash) => (&value[..slash], Some(value[slash + 1..].parse().ok()?)),
+            None => (value, None),
+        };
+        let network = canonical(network.parse().ok()?);
+        let (_, width) = bits(network);
+        let prefix_length = prefix_length.unwrap_or(width);
+        if prefix_length > width {
+            return None;
+        }
+        Some(Cidr {
+            network,
+            prefix_length,
+        })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let (network, width) = bits(self.network);
+        let (address, address_width) = bits(canonical(address));
+        if width != address_width {
+            return false;
+        }
+        let shift = u32::from(width - self.prefix_length);
+        network.checked_shr(shift).unwrap_or(0) == address.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "{}/{}", self.network, self.prefix_length)
+    }
+}
+
+pub fn parse_ranges<'a, I: IntoIterator<Item = &'a str>>(values: I) -> Option<Vec<Cidr>> {
+    values.into_iter().map(Cidr::parse).collect()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Rejection {
+    Denied,
+    NotAllowed,
+    TooManyConnections,
+    TooManyConnectionsFromAddress,
+}
+
+impl Rejection {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Rejection::Denied => "its address is denied",
+            Rejection::NotAllowed => "its address isn't allowed",
+            Rejection::TooManyConnections => "the inbound connection limit is reached",
+            Rejection::TooManyConnectionsFromAddress => {
+                "its address reached the per-address connection limit"
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct RejectionCounts {
+    pub denied: u64,
+    pub not_allowed: u64,
+    pub too_many_connections: u64,
+    pub too_many_connections_from_address: u64,
+}
+
+/// Decides which incoming sockets are served. Deny ranges take precedence
+/// over allow ranges, and an empty allow list allows every address. Changes
+/// only apply to connections accepted afterwards.
+pub struct InboundPolicy {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    pub max_connections: usize,
+    pub max_connections_per_address: usize,
+    connections: HashMap<IpAddr, usize>,
+    connection_count: usize,
+    rejected: RejectionCounts,
+}
+
+impl InboundPolicy {
+    pub fn new(
+        allow: Vec<Cidr>,
+        deny: Vec<Cidr>,
+        max_connections: usize,
+        max_connections_per_address: usize,
+    ) -> InboundPolicy {
+        InboundPolicy {
+            allow,
+            deny,
+            max_connections,
+            max_connections_per_address,
+            connections: HashMap::new(),
+            connection_count: 0,
+            rejected: RejectionCounts::default(),
+        }
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.connection_count
+    }
+
+    pub fn rejected(&self) -> RejectionCounts {
+        self.rejected
+    }
+
+    fn check(&self, address: IpAddr) -> Result<(), Rejection> {
+        if self.deny.iter().any(|range| range.contains(address)) {
+            return Err(Rejection::Denied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|range| range.contains(address)) {
+            return Err(Rejection::NotAllowed);
+        }
+        if self.connection_count >= self.max_connections {
+            return Err(Rejection::TooManyConnections);
+        }
+        if self.connections.get(&address).copied().unwrap_or(0) >= self.max_connections_per_address
+        {
+            return Err(Rejection::TooManyConnectionsFromAddress);
+        }
+        Ok(())
+    }
+}
+
+/// Counts as an inbound connection until dropped.
+pub struct Permit {
+    policy: Rc<RefCell<InboundPolicy>>,
+    address: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut policy = self.policy.borrow_mut();
+        policy.connection_count -= 1;
+        if let Some(count) = policy.connections.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                policy.connections.remove(&self.address);
+            }
+        }
+    }
+}
+
+pub fn admit(policy: &Rc<RefCell<InboundPolicy>>, address: IpAddr) -> Result<Permit, Rejection> {
+    let address = canonical(address);
+    let mut locked = policy.borrow_mut();
+    if let Err(rejection) = locked.check(address) {
+        let counter = match rejection {
+            Rejection::Denied => &mut locked.rejected.denied,
+            Rejection::NotAllowed => &mut locked.rejected.not_allowed,
+            Rejection::TooManyConnections => &mut locked.rejected.too_many_connections,
+            Rejection::TooManyConnectionsFromAddress => {
+                &mut locked.rejected.too_many_connections_from_address
+            }
+        };
+        *counter += 1;
+        return Err(rejection);
+    }
+    locked.connection_count += 1;
+    *locked.connections.entry(address).or_insert(0) += 1;
+    Ok(Permit {
+        policy: policy.clone(),
+        address,
+    })
+}
+
+/// Logs and counts rejected sockets.
+pub fn admit_socket(
+    policy: &Rc<RefCell<InboundPolicy>>,
+    socket: &async_std::net::TcpStream,
+) -> Option<Permit> {
+    let address = match socket.peer_addr() {
+        Ok(address) => address,
+        Err(error) => {
+            log::warning(format!(
+                "Can't get the address of an incoming socket due to error {:?}",
+                error
+            ));
+            return None;
+        }
+    };
+    match admit(policy, address.ip()) {
+        Ok(permit) => Some(permit),
+        Err(rejection) => {
+            log::notice(format!(
+                "Rejected connection from {} because {}",
+                address,
+                rejection.reason()
+            ));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(range: &str, address: &str) -> bool {
+        Cidr::parse(range)
+            .unwrap()
+            .contains(address.parse().unwrap())
+    }
+
+    #[test]
+    fn ranges_match_their_prefix() {
+        assert!(contains("0.0.0.0/0", "203.0.113.1"));
+        assert!(contains("0.0.0.0/0", "::ffff:203.0.113.1"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(!contains("::/0", "203.0.113.1"));
+        assert!(contains("10.0.0.0/8", "10.255.0.1"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("203.0.113.1/32", "203.0.113.1"));
+        assert!(!contains("203.0.113.1/32", "203.0.113.2"));
+        assert!(contains("203.0.113.1", "::ffff:203.0.113.1"));
+        assert!(contains("2001:db8::1/128", "2001:db8::1"));
+        assert!(!contains("2001:db8::1/128", "2001:db8::2"));
+        assert!(contains("2001:db8::/32", "2001:db8:ffff::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+    }
+
+    #[test]
+    fn ranges_are_printed_with_their_prefix() {
+        assert_eq!(Cidr::parse("10.0.0.1").unwrap().to_string(), "10.0.0.1/32");
+        assert_eq!(Cidr::parse("::1").unwrap().to_string(), "::1/128");
+        assert_eq!(
+            Cidr::parse("::ffff:10.0.0.1/32").unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+    }
+
+    #[test]
+    fn malformed_ranges_are_rejected() {
+        for range in &[
+            "",
+            "/8",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0/8",
+            "example.com/8",
+        ] {
+            assert!(Cidr::parse(range).is_none(), "{}", range);
+        }
+        assert!(parse_ranges(vec!["10.0.0.0/8", "nonsense"]).is_none());
+        assert_eq!(parse_ranges(vec!["10.0.0.0/8", "::/0"]).unwrap().len(), 2);
+    }
+}
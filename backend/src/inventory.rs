@@ -1,75 +1,772 @@
 use crate::die_on_error::die_on_error;
 use crate::message_hash::message_hash;
-use async_std::sync::channel;
-use async_std::task;
-use rusqlite::params;
+use crate::proof_of_work::surplus;
+use crate::quota::{EvictionPolicy, Quota};
+use chrono::Utc;
+use rusqlite::types::Value;
+use rusqlite::{params, Transaction};
 use serde::{Deserialize, Serialize};
 
 type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
 
-pub fn exists(pool: Pool, hash: &[u8]) -> bool {
-    let connection = die_on_error(pool.get());
-    let mut statement =
-        die_on_error(connection.prepare(include_str!("../sql/B. RPC/2. Retrieve message.sql")));
-    die_on_error(statement.exists(params![hash]))
-}
-
-pub fn insert(pool: Pool, payload: &[u8], nonce: i64, expiration_time: i64) {
-    die_on_error(die_on_error(pool.get()).execute(
-        include_str!("../sql/B. RPC/4. Purge expired messages.sql"),
-        params![],
-    ));
-    die_on_error(die_on_error(pool.get()).execute(
-        include_str!("../sql/B. RPC/3. Put message.sql"),
-        params![
-            message_hash(&payload, expiration_time).to_vec(),
-            payload,
-            nonce,
-            expiration_time
-        ],
-    ));
-}
+/// Shared by every task that reads or writes messages. Calls block, so they
+/// are made from `task::spawn`.
+pub type Inventory = std::sync::Arc<dyn InventoryStore>;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub payload: Vec<u8>,
     pub nonce: i64,
     pub expiration_time: i64,
 }
 
-pub fn retrieve(pool: Pool, hash: &[u8]) -> Option<Message> {
-    let connection = die_on_error(pool.get());
-    let mut statement =
-        die_on_error(connection.prepare(include_str!("../sql/B. RPC/2. Retrieve message.sql")));
-    let mut rows = die_on_error(statement.query(params![hash]));
-    while let Some(row) = die_on_error(rows.next()) {
-        let payload: Vec<u8> = die_on_error(row.get(0));
-        let nonce: i64 = die_on_error(row.get(1));
-        let expiration_time: i64 = die_on_error(row.get(2));
-        return Some(Message {
-            payload,
+/// What became of a message handed to the inventory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Insertion {
+    Inserted,
+    /// Already stored, so nothing changed.
+    Duplicate,
+    /// Rejected under the quota.
+    Full,
+}
+
+/// How far two nodes have synced their insertion sequences.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCursor {
+    /// Position in the peer's sequence up to which we have pulled.
+    pub theirs: i64,
+    /// Position in our sequence up to which we have pushed.
+    pub ours: i64,
+    /// When both inventories were last compared in full.
+    pub reconciled_at: i64,
+}
+
+/// Where messages are kept. Expired messages are never returned, even
+/// before they are purged. Stores enforce their quota on insertion.
+pub trait InventoryStore: Send + Sync {
+    fn exists(&self, hash: &[u8]) -> bool;
+
+    fn retrieve(&self, hash: &[u8]) -> Option<Message>;
+
+    /// Inserts all messages at once. Every new message is assigned the next
+    /// insertion sequence number. Only new messages make room under the
+    /// quota.
+    fn insert_many(&self, messages: &[Message]) -> Vec<Insertion>;
+
+    fn insert(&self, payload: &[u8], nonce: i64, expiration_time: i64) -> Insertion {
+        self.insert_many(&[Message {
+            payload: payload.to_vec(),
             nonce,
             expiration_time,
-        });
+        }])[0]
     }
-    None
+
+    fn hashes(&self) -> Vec<Vec<u8>>;
+
+    /// Hashes from `lower_bound` inclusive to `upper_bound` exclusive, in
+    /// order.
+    fn hashes_in_range(&self, lower_bound: &[u8], upper_bound: &[u8]) -> Vec<Vec<u8>>;
+
+    /// Returns the hashes inserted after `cursor`, along with the cursor to
+    /// pass next time.
+    fn hashes_since(&self, cursor: i64) -> (Vec<Vec<u8>>, i64);
+
+    /// Hashes of messages stored before insertions were numbered, which
+    /// `hashes_since` never returns.
+    fn unsequenced_hashes(&self) -> Vec<Vec<u8>>;
+
+    /// How far we have synced with the node holding `public_key`. Kept with
+    /// the messages, since it is only valid as long as they are.
+    fn peer_cursor(&self, public_key: &[u8]) -> Option<PeerCursor>;
+
+    fn set_peer_cursor(&self, public_key: &[u8], cursor: PeerCursor);
+
+    /// Deletes expired messages and returns their hashes.
+    fn purge_expired(&self) -> Vec<Vec<u8>>;
+
+    /// The earliest expiration time in the inventory, if it isn't empty.
+    fn next_expiration_time(&self) -> Option<i64>;
 }
 
-pub fn hashes(pool: Pool) -> async_std::sync::Receiver<Vec<u8>> {
-    let (tx, rx) = channel(1);
-    task::spawn(async move {
-        let connection = die_on_error(pool.get());
-        let mut statement =
-            die_on_error(connection.prepare(include_str!("../sql/B. RPC/1. Retrieve hashes.sql")));
-        let mut rows = die_on_error(statement.query(params![]));
-        let tx = std::sync::Arc::new(tx);
+/// Expiration times are compared as integers against this, so the queries
+/// can use the expiration time index.
+pub fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+/// Keeps messages in the database.
+pub struct SqliteInventory {
+    pool: Pool,
+    quota: Quota,
+    proof_of_work_key: Option<[u8; 32]>,
+}
+
+impl SqliteInventory {
+    pub fn new(pool: Pool, quota: Quota, proof_of_work_key: Option<[u8; 32]>) -> SqliteInventory {
+        SqliteInventory {
+            pool,
+            quota,
+            proof_of_work_key,
+        }
+    }
+
+    /// Evicts the messages the quota policy ranks below the incoming one
+    /// until it fits. Returns false, evicting nothing, if it doesn't fit that
+    /// way.
+    fn make_room(
+        &self,
+        transaction: &Transaction,
+        expiration_time: i64,
+        size: i64,
+        surplus: f64,
+    ) -> bool {
+        let quota = &self.quota;
+        if quota.max_bytes.is_none() && quota.max_messages.is_none() {
+            return true;
+        }
+        let max_bytes = quota.max_bytes.map_or(i64::MAX, |max| max as i64);
+        let max_messages = quota.max_messages.map_or(i64::MAX, |max| max as i64);
+        if size > max_bytes {
+            return false;
+        }
+        let (count, bytes): (i64, i64) = die_on_error(transaction.query_row(
+            include_str!("../sql/B. RPC/13. Retrieve inventory usage.sql"),
+            params![],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ));
+        let mut excess_messages = count + 1 - max_messages;
+        let mut excess_bytes = bytes + size - max_bytes;
+        if excess_messages <= 0 && excess_bytes <= 0 {
+            return true;
+        }
+        let (query, rank) = match quota.eviction_policy {
+            EvictionPolicy::SoonestExpiring => (
+                include_str!("../sql/B. RPC/14. Retrieve soonest expiring messages.sql"),
+                Value::Integer(expiration_time),
+            ),
+            EvictionPolicy::Largest => (
+                include_str!("../sql/B. RPC/15. Retrieve largest messages.sql"),
+                Value::Integer(size),
+            ),
+            EvictionPolicy::LowestSurplus => (
+                include_str!("../sql/B. RPC/16. Retrieve messages with lowest surplus.sql"),
+                Value::Real(surplus),
+            ),
+        };
+        let mut evicted: Vec<Vec<u8>> = Vec::new();
+        {
+            let mut statement = die_on_error(transaction.prepare(query));
+            let mut rows = die_on_error(statement.query(params![rank]));
+            while excess_messages > 0 || excess_bytes > 0 {
+                let row = match die_on_error(rows.next()) {
+                    Some(row) => row,
+                    None => return false,
+                };
+                evicted.push(die_on_error(row.get(0)));
+                excess_messages -= 1;
+                excess_bytes -= die_on_error(row.get::<_, i64>(1));
+            }
+        }
+        for hash in &evicted {
+            die_on_error(transaction.execute(
+                include_str!("../sql/B. RPC/11. Remove message.sql"),
+                params![hash],
+            ));
+        }
+        true
+    }
+
+    fn query_hashes(&self, query: &str, parameters: &[&dyn rusqlite::ToSql]) -> Vec<Vec<u8>> {
+        let connection = die_on_error(self.pool.get());
+        let mut statement = die_on_error(connection.prepare(query));
+        let mut rows = die_on_error(statement.query(parameters));
+        let mut hashes = Vec::new();
         while let Some(row) = die_on_error(rows.next()) {
-            let stuff: Vec<u8> = die_on_error(row.get(0));
-            let tx = tx.clone();
-            task::spawn(async move {
-                tx.send(stuff).await;
-            });
-        }
-    });
-    rx
+            hashes.push(die_on_error(row.get(0)));
+        }
+        hashes
+    }
+}
+
+impl InventoryStore for SqliteInventory {
+    fn exists(&self, hash: &[u8]) -> bool {
+        let connection = die_on_error(self.pool.get());
+        let mut statement =
+            die_on_error(connection.prepare(include_str!("../sql/B. RPC/2. Retrieve message.sql")));
+        die_on_error(statement.exists(params![hash, now()]))
+    }
+
+    fn retrieve(&self, hash: &[u8]) -> Option<Message> {
+        let connection = die_on_error(self.pool.get());
+        let mut statement =
+            die_on_error(connection.prepare(include_str!("../sql/B. RPC/2. Retrieve message.sql")));
+        let mut rows = die_on_error(statement.query(params![hash, now()]));
+        die_on_error(rows.next()).map(|row| Message {
+            payload: die_on_error(row.get(0)),
+            nonce: die_on_error(row.get(1)),
+            expiration_time: die_on_error(row.get(2)),
+        })
+    }
+
+    fn insert_many(&self, messages: &[Message]) -> Vec<Insertion> {
+        let mut connection = die_on_error(self.pool.get());
+        let transaction = die_on_error(connection.transaction());
+        let mut outcomes = Vec::new();
+        for message in messages {
+            let hash = message_hash(&message.payload, message.expiration_time).to_vec();
+            let mut statement = die_on_error(
+                transaction.prepare(include_str!("../sql/B. RPC/17. Message exists.sql")),
+            );
+            if die_on_error(statement.exists(params![hash])) {
+                outcomes.push(Insertion::Duplicate);
+                continue;
+            }
+            let size = message.payload.len() as i64;
+            let surplus = surplus(
+                &message.payload,
+                message.nonce,
+                message.expiration_time,
+                self.proof_of_work_key.as_ref().map(|key| &key[..]),
+            );
+            if !self.make_room(&transaction, message.expiration_time, size, surplus) {
+                outcomes.push(Insertion::Full);
+                continue;
+            }
+            die_on_error(transaction.execute(
+                include_str!("../sql/B. RPC/8. Increment insertion counter.sql"),
+                params![],
+            ));
+            die_on_error(transaction.execute(
+                include_str!("../sql/B. RPC/3. Put message.sql"),
+                params![
+                    hash,
+                    message.payload,
+                    message.nonce,
+                    message.expiration_time,
+                    size,
+                    surplus
+                ],
+            ));
+            outcomes.push(Insertion::Inserted);
+        }
+        die_on_error(transaction.commit());
+        outcomes
+    }
+
+    fn hashes(&self) -> Vec<Vec<u8>> {
+        self.query_hashes(
+            include_str!("../sql/B. RPC/1. Retrieve hashes.sql"),
+            params![now()],
+        )
+    }
+
+    fn hashes_in_range(&self, lower_bound: &[u8], upper_bound: &[u8]) -> Vec<Vec<u8>> {
+        self.query_hashes(
+            include_str!("../sql/B. RPC/5. Retrieve hashes in range.sql"),
+            params![lower_bound, upper_bound, now()],
+        )
+    }
+
+    fn hashes_since(&self, cursor: i64) -> (Vec<Vec<u8>>, i64) {
+        let next_cursor: i64 = die_on_error(die_on_error(self.pool.get()).query_row(
+            include_str!("../sql/B. RPC/7. Retrieve insertion counter.sql"),
+            params![],
+            |row| row.get(0),
+        ));
+        let hashes = self.query_hashes(
+            include_str!("../sql/B. RPC/6. Retrieve hashes since cursor.sql"),
+            params![cursor, next_cursor, now()],
+        );
+        (hashes, next_cursor)
+    }
+
+    fn unsequenced_hashes(&self) -> Vec<Vec<u8>> {
+        self.query_hashes(
+            include_str!("../sql/B. RPC/18. Retrieve unsequenced hashes.sql"),
+            params![now()],
+        )
+    }
+
+    fn peer_cursor(&self, public_key: &[u8]) -> Option<PeerCursor> {
+        let connection = die_on_error(self.pool.get());
+        let mut statement = die_on_error(
+            connection.prepare(include_str!("../sql/B. RPC/9. Retrieve peer cursor.sql")),
+        );
+        let mut rows = die_on_error(statement.query(params![public_key]));
+        die_on_error(rows.next()).map(|row| PeerCursor {
+            theirs: die_on_error(row.get(0)),
+            ours: die_on_error(row.get(1)),
+            reconciled_at: die_on_error(row.get(2)),
+        })
+    }
+
+    fn set_peer_cursor(&self, public_key: &[u8], cursor: PeerCursor) {
+        die_on_error(die_on_error(self.pool.get()).execute(
+            include_str!("../sql/B. RPC/10. Put peer cursor.sql"),
+            params![public_key, cursor.theirs, cursor.ours, cursor.reconciled_at],
+        ));
+    }
+
+    fn purge_expired(&self) -> Vec<Vec<u8>> {
+        let mut connection = die_on_error(self.pool.get());
+        let transaction = die_on_error(connection.transaction());
+        let mut hashes = Vec::new();
+        {
+            let mut statement = die_on_error(
+                transaction.prepare(include_str!("../sql/B. RPC/4. Retrieve expired hashes.sql")),
+            );
+            let mut rows = die_on_error(statement.query(params![now()]));
+            while let Some(row) = die_on_error(rows.next()) {
+                hashes.push(die_on_error(row.get::<_, Vec<u8>>(0)));
+            }
+        }
+        for hash in &hashes {
+            die_on_error(transaction.execute(
+                include_str!("../sql/B. RPC/11. Remove message.sql"),
+                params![hash],
+            ));
+        }
+        die_on_error(transaction.commit());
+        hashes
+    }
+
+    fn next_expiration_time(&self) -> Option<i64> {
+        die_on_error(die_on_error(self.pool.get()).query_row(
+            include_str!("../sql/B. RPC/12. Retrieve next expiration time.sql"),
+            params![],
+            |row| row.get(0),
+        ))
+    }
+}
+
+/// Both stores run the same checks, so they can't drift apart.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_inventory::MemoryInventory;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    fn stores(quota: Quota) -> Vec<(&'static str, Box<dyn InventoryStore>)> {
+        let pool = std::sync::Arc::new(
+            r2d2::Pool::builder()
+                .max_size(1)
+                .build(SqliteConnectionManager::memory())
+                .unwrap(),
+        );
+        crate::migrations::migrate(pool.clone()).unwrap();
+        vec![
+            ("sqlite", Box::new(SqliteInventory::new(pool, quota, None))),
+            ("memory", Box::new(MemoryInventory::new(quota, None))),
+        ]
+    }
+
+    fn quota(
+        max_bytes: Option<u64>,
+        max_messages: Option<u64>,
+        eviction_policy: EvictionPolicy,
+    ) -> Quota {
+        Quota {
+            max_bytes,
+            max_messages,
+            eviction_policy,
+        }
+    }
+
+    fn unlimited() -> Quota {
+        quota(None, None, EvictionPolicy::SoonestExpiring)
+    }
+
+    fn message(payload: &[u8], nonce: i64, expires_in: i64) -> Message {
+        Message {
+            payload: payload.to_vec(),
+            nonce,
+            expiration_time: now() + expires_in,
+        }
+    }
+
+    fn hash(message: &Message) -> Vec<u8> {
+        message_hash(&message.payload, message.expiration_time).to_vec()
+    }
+
+    fn sorted(mut hashes: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        hashes.sort();
+        hashes
+    }
+
+    /// Messages of the same size and lifetime, from the lowest surplus up.
+    fn ranked_by_surplus(count: u8, size: usize) -> Vec<Message> {
+        let mut messages: Vec<(f64, Message)> = (0..count)
+            .map(|i| {
+                let message = message(&vec![i; size], 0, 3600);
+                let surplus = surplus(&message.payload, 0, message.expiration_time, None);
+                (surplus, message)
+            })
+            .collect();
+        messages.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+        messages.into_iter().map(|(_, message)| message).collect()
+    }
+
+    /// Three messages of the same size and the policy's rank, from the
+    /// lowest up.
+    fn ranked(eviction_policy: EvictionPolicy) -> Vec<Message> {
+        match eviction_policy {
+            EvictionPolicy::SoonestExpiring => vec![
+                message(b"aaaa", 0, 100),
+                message(b"bbbb", 0, 200),
+                message(b"cccc", 0, 300),
+            ],
+            EvictionPolicy::Largest => vec![
+                message(b"aaaaaa", 0, 100),
+                message(b"bbbbb", 0, 100),
+                message(b"cccc", 0, 100),
+            ],
+            EvictionPolicy::LowestSurplus => ranked_by_surplus(3, 4),
+        }
+    }
+
+    const POLICIES: [EvictionPolicy; 3] = [
+        EvictionPolicy::SoonestExpiring,
+        EvictionPolicy::Largest,
+        EvictionPolicy::LowestSurplus,
+    ];
+
+    #[test]
+    fn duplicates_are_not_inserted_again() {
+        for (name, store) in stores(unlimited()) {
+            let first = message(b"first", 1, 100);
+            let second = message(b"second", 2, 100);
+            assert_eq!(
+                store.insert_many(std::slice::from_ref(&first)),
+                [Insertion::Inserted]
+            );
+            assert_eq!(
+                store.insert_many(&[second.clone(), first.clone(), second.clone()]),
+                [
+                    Insertion::Inserted,
+                    Insertion::Duplicate,
+                    Insertion::Duplicate
+                ],
+                "{}",
+                name
+            );
+            assert!(store.exists(&hash(&first)), "{}", name);
+            let retrieved = store.retrieve(&hash(&second)).unwrap();
+            assert_eq!(retrieved.payload, second.payload, "{}", name);
+            assert_eq!(retrieved.nonce, 2, "{}", name);
+            assert_eq!(
+                sorted(store.hashes()),
+                sorted(vec![hash(&first), hash(&second)]),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn expired_messages_are_hidden_then_purged() {
+        for (name, store) in stores(unlimited()) {
+            let expired = message(b"expired", 0, -10);
+            let live = message(b"live", 0, 100);
+            store.insert_many(&[expired.clone(), live.clone()]);
+            assert!(!store.exists(&hash(&expired)), "{}", name);
+            assert!(store.retrieve(&hash(&expired)).is_none(), "{}", name);
+            assert_eq!(store.hashes(), [hash(&live)], "{}", name);
+            assert_eq!(
+                store.hashes_in_range(&[0], &[0xff; 65]),
+                [hash(&live)],
+                "{}",
+                name
+            );
+            assert_eq!(store.hashes_since(0).0, [hash(&live)], "{}", name);
+            assert_eq!(
+                store.next_expiration_time(),
+                Some(expired.expiration_time),
+                "{}",
+                name
+            );
+            assert_eq!(store.purge_expired(), [hash(&expired)], "{}", name);
+            assert!(store.purge_expired().is_empty(), "{}", name);
+            assert_eq!(
+                store.next_expiration_time(),
+                Some(live.expiration_time),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn hashes_since_returns_new_insertions_in_order() {
+        for (name, store) in stores(unlimited()) {
+            let messages: Vec<Message> = (0..3).map(|i| message(&[i], 0, 100)).collect();
+            store.insert_many(&messages);
+            let (hashes, cursor) = store.hashes_since(0);
+            assert_eq!(
+                hashes,
+                messages.iter().map(hash).collect::<Vec<_>>(),
+                "{}",
+                name
+            );
+            assert_eq!(cursor, 3, "{}", name);
+            assert_eq!(store.hashes_since(cursor), (Vec::new(), 3), "{}", name);
+            store.insert_many(&messages);
+            assert_eq!(store.hashes_since(cursor).1, 3, "{}", name);
+            let next = message(b"next", 0, 100);
+            store.insert_many(std::slice::from_ref(&next));
+            assert_eq!(
+                store.hashes_since(cursor),
+                (vec![hash(&next)], 4),
+                "{}",
+                name
+            );
+            assert_eq!(store.hashes_since(1).0.len(), 3, "{}", name);
+        }
+    }
+
+    #[test]
+    fn each_policy_evicts_the_lowest_ranked_message() {
+        for policy in &POLICIES {
+            for (name, store) in stores(quota(None, Some(2), *policy)) {
+                let messages = ranked(*policy);
+                assert_eq!(
+                    store.insert_many(&messages),
+                    [Insertion::Inserted; 3],
+                    "{} {:?}",
+                    name,
+                    policy
+                );
+                assert!(!store.exists(&hash(&messages[0])), "{} {:?}", name, policy);
+                assert!(store.exists(&hash(&messages[1])), "{} {:?}", name, policy);
+                assert!(store.exists(&hash(&messages[2])), "{} {:?}", name, policy);
+            }
+        }
+    }
+
+    fn stored(store: &dyn InventoryStore, messages: &[Message]) -> Vec<bool> {
+        messages
+            .iter()
+            .map(|message| store.exists(&hash(message)))
+            .collect()
+    }
+
+    #[test]
+    fn byte_limit_evicts_until_the_message_fits() {
+        for (name, store) in stores(quota(Some(10), None, EvictionPolicy::SoonestExpiring)) {
+            let messages = [
+                message(b"aaaa", 0, 100),
+                message(b"bbbb", 0, 200),
+                message(b"cccccc", 0, 300),
+                message(b"dddddddd", 0, 400),
+            ];
+            assert_eq!(
+                store.insert_many(&messages[..3]),
+                [Insertion::Inserted; 3],
+                "{}",
+                name
+            );
+            assert_eq!(
+                stored(&*store, &messages),
+                [false, true, true, false],
+                "{}",
+                name
+            );
+            assert_eq!(
+                store.insert_many(&messages[3..]),
+                [Insertion::Inserted],
+                "{}",
+                name
+            );
+            assert_eq!(
+                stored(&*store, &messages),
+                [false, false, false, true],
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn message_limit_evicts_one_message_per_insertion() {
+        for policy in &POLICIES {
+            for (name, store) in stores(quota(None, Some(1), *policy)) {
+                let messages = ranked(*policy);
+                for (i, message) in messages.iter().enumerate() {
+                    assert_eq!(
+                        store.insert(&message.payload, message.nonce, message.expiration_time),
+                        Insertion::Inserted,
+                        "{} {:?}",
+                        name,
+                        policy
+                    );
+                    assert_eq!(store.hashes().len(), 1, "{} {:?}", name, policy);
+                    assert!(store.exists(&hash(&messages[i])), "{} {:?}", name, policy);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn messages_over_the_byte_limit_are_rejected() {
+        for policy in &POLICIES {
+            for (name, store) in stores(quota(Some(10), None, *policy)) {
+                let small = message(b"a", 0, 100);
+                let large = message(&[0xff; 11], 1 << 40, 1000);
+                store.insert_many(std::slice::from_ref(&small));
+                assert_eq!(
+                    store.insert_many(std::slice::from_ref(&large)),
+                    [Insertion::Full],
+                    "{} {:?}",
+                    name,
+                    policy
+                );
+                assert_eq!(
+                    stored(&*store, &[small, large]),
+                    [true, false],
+                    "{} {:?}",
+                    name,
+                    policy
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_lowest_ranked_message_is_rejected_without_evicting() {
+        for policy in &POLICIES {
+            for (name, store) in stores(quota(None, Some(2), *policy)) {
+                let messages = ranked(*policy);
+                store.insert_many(&messages[1..]);
+                assert_eq!(
+                    store.insert_many(&messages[..1]),
+                    [Insertion::Full],
+                    "{} {:?}",
+                    name,
+                    policy
+                );
+                assert_eq!(
+                    stored(&*store, &messages),
+                    [false, true, true],
+                    "{} {:?}",
+                    name,
+                    policy
+                );
+                // Messages already stored don't need room.
+                assert_eq!(
+                    store.insert_many(&messages[1..2]),
+                    [Insertion::Duplicate],
+                    "{} {:?}",
+                    name,
+                    policy
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_is_evicted_unless_the_message_fits_afterwards() {
+        for (name, store) in stores(quota(Some(10), None, EvictionPolicy::SoonestExpiring)) {
+            let messages = [
+                message(b"aaaa", 0, 100),
+                message(b"bbbbbb", 0, 300),
+                message(b"cccccccc", 0, 200),
+            ];
+            store.insert_many(&messages[..2]);
+            assert_eq!(
+                store.insert_many(&messages[2..]),
+                [Insertion::Full],
+                "{}",
+                name
+            );
+            assert_eq!(stored(&*store, &messages), [true, true, false], "{}", name);
+        }
+    }
+
+    #[test]
+    fn equally_ranked_messages_are_not_evicted() {
+        for (name, store) in stores(quota(None, Some(1), EvictionPolicy::SoonestExpiring)) {
+            let first = message(b"first", 0, 100);
+            let second = message(b"second", 0, 100);
+            store.insert_many(std::slice::from_ref(&first));
+            assert_eq!(
+                store.insert_many(std::slice::from_ref(&second)),
+                [Insertion::Full],
+                "{}",
+                name
+            );
+            assert_eq!(stored(&*store, &[first, second]), [true, false], "{}", name);
+        }
+    }
+
+    fn median_ms<T>(repeat: usize, mut run: impl FnMut() -> T) -> f64 {
+        let mut timings: Vec<f64> = (0..repeat)
+            .map(|_| {
+                let start = std::time::Instant::now();
+                drop(run());
+                start.elapsed().as_secs_f64() * 1000.0
+            })
+            .collect();
+        timings.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        timings[repeat / 2]
+    }
+
+    /// Times the store methods the backend calls most often against 100k
+    /// messages, an eighth of them expired. Run it before and after touching
+    /// `sql/`:
+    ///
+    ///     cargo test --release bench_inventory -- --ignored --nocapture
+    ///
+    /// `BENCH_ROWS` changes the number of messages.
+    #[test]
+    #[ignore]
+    fn bench_inventory() {
+        let rows: u64 = std::env::var("BENCH_ROWS")
+            .map(|rows| rows.parse().unwrap())
+            .unwrap_or(100_000);
+        let path = std::env::temp_dir().join(format!("bench-inventory-{}.db", std::process::id()));
+        let pool = std::sync::Arc::new(
+            r2d2::Pool::builder()
+                .build(SqliteConnectionManager::file(&path))
+                .unwrap(),
+        );
+        crate::migrations::migrate(pool.clone()).unwrap();
+        let stores: Vec<(&str, Box<dyn InventoryStore>)> = vec![
+            (
+                "sqlite",
+                Box::new(SqliteInventory::new(pool, unlimited(), None)),
+            ),
+            ("memory", Box::new(MemoryInventory::new(unlimited(), None))),
+        ];
+        let messages: Vec<Message> = (0..rows)
+            .map(|i| {
+                let mut payload = i.to_be_bytes().to_vec();
+                payload.resize(200, 0);
+                message(&payload, 0, if i < rows / 8 { -60 } else { 86400 })
+            })
+            .collect();
+        let live = hash(&messages[rows as usize - 1]);
+        println!("{} messages, 200-byte payloads, 1/8 expired", rows);
+        println!("{:<8} {:<10} {:>10}", "store", "method", "ms");
+        for (name, store) in stores {
+            store.insert_many(&messages);
+            let timings = [
+                ("hashes", median_ms(20, || store.hashes())),
+                ("exists", median_ms(20, || store.exists(&live))),
+                ("retrieve", median_ms(20, || store.retrieve(&live))),
+                (
+                    "range",
+                    median_ms(20, || store.hashes_in_range(&[0x10], &[0x20])),
+                ),
+                (
+                    "since",
+                    median_ms(20, || store.hashes_since(rows as i64 - 1000)),
+                ),
+                ("next", median_ms(20, || store.next_expiration_time())),
+                ("purge", median_ms(1, || store.purge_expired())),
+            ];
+            for (method, milliseconds) in &timings {
+                println!("{:<8} {:<10} {:>10.3}", name, method, milliseconds);
+            }
+        }
+        std::fs::remove_file(path).unwrap();
+    }
 }
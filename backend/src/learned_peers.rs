@@ -0,0 +1,112 @@
+use crate::die_on_error::die_on_error;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
+
+/// An address learned through peer exchange. `source` names the node that
+/// handed it out. Timestamps are in seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LearnedPeer {
+    pub address: String,
+    pub source: String,
+    pub last_seen: i64,
+    pub last_success: Option<i64>,
+    pub last_failure: Option<i64>,
+    pub failure_count: i64,
+}
+
+fn read_learned_peer(row: &rusqlite::Row) -> LearnedPeer {
+    LearnedPeer {
+        address: die_on_error(row.get(0)),
+        source: die_on_error(row.get(1)),
+        last_seen: die_on_error(row.get(2)),
+        last_success: die_on_error(row.get(3)),
+        last_failure: die_on_error(row.get(4)),
+        failure_count: die_on_error(row.get(5)),
+    }
+}
+
+/// Returns whether the peer wasn't known before.
+pub fn insert(pool: Pool, address: &str, source: &str) -> bool {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/H. Learned peers/1. Put learned peer.sql"),
+        params![address, source],
+    )) > 0
+}
+
+/// Returns whether the peer was known.
+pub fn touch(pool: Pool, address: &str) -> bool {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/H. Learned peers/2. Touch learned peer.sql"),
+        params![address],
+    )) > 0
+}
+
+pub fn remove(pool: Pool, address: &str) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/H. Learned peers/3. Remove learned peer.sql"),
+        params![address],
+    ));
+}
+
+pub fn list(pool: Pool) -> Vec<LearnedPeer> {
+    let connection = die_on_error(pool.get());
+    let mut statement = die_on_error(connection.prepare(include_str!(
+        "../sql/H. Learned peers/4. Retrieve learned peers.sql"
+    )));
+    let mut rows = die_on_error(statement.query(params![]));
+    let mut peers = Vec::new();
+    while let Some(row) = die_on_error(rows.next()) {
+        peers.push(read_learned_peer(row));
+    }
+    peers
+}
+
+pub fn retrieve(pool: Pool, address: &str) -> Option<LearnedPeer> {
+    let connection = die_on_error(pool.get());
+    let mut statement = die_on_error(connection.prepare(include_str!(
+        "../sql/H. Learned peers/5. Retrieve learned peer.sql"
+    )));
+    let mut rows = die_on_error(statement.query(params![address]));
+    die_on_error(rows.next()).map(read_learned_peer)
+}
+
+pub fn record_success(pool: Pool, address: &str) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/H. Learned peers/6. Record success.sql"),
+        params![address],
+    ));
+}
+
+pub fn record_failure(pool: Pool, address: &str) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/H. Learned peers/7. Record failure.sql"),
+        params![address],
+    ));
+}
+
+pub fn count(pool: Pool) -> i64 {
+    die_on_error(die_on_error(pool.get()).query_row(
+        include_str!("../sql/H. Learned peers/8. Count learned peers.sql"),
+        params![],
+        |row| row.get(0),
+    ))
+}
+
+pub fn count_from_source(pool: Pool, source: &str) -> i64 {
+    die_on_error(die_on_error(pool.get()).query_row(
+        include_str!("../sql/H. Learned peers/9. Count learned peers from source.sql"),
+        params![source],
+        |row| row.get(0),
+    ))
+}
+
+/// Forgets peers nobody has mentioned for `lifetime` seconds, and peers
+/// that failed `max_failures` times in a row.
+pub fn expire(pool: Pool, lifetime: i64, max_failures: i64) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/H. Learned peers/10. Expire learned peers.sql"),
+        params![lifetime, max_failures],
+    ));
+}
@@ -1,28 +1,76 @@
 use clap::{App, Arg};
+use crypto::blake2b::Blake2b;
+use crypto::digest::Digest;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::params;
-use std::include_str;
 use std::net::SocketAddr;
 use std::process::exit;
+mod announcer;
 mod connect;
 mod die_on_error;
+mod discovery;
+mod envelope;
+mod iblt;
+mod identity;
+mod inbound_policy;
 mod inventory;
+mod learned_peers;
 mod log;
+mod memory_inventory;
 mod message_hash;
+mod migrations;
 mod mpmc_manual_reset_event;
+mod noise;
+mod peer_manager;
+mod peers;
+mod pex;
 mod proof_of_work;
+mod protocol;
+mod quota;
+mod range_reconcile;
+mod ratchet;
+mod rate_limit;
 mod reconcile_client;
 mod reconcile_server;
+mod signed_envelope;
+mod socks5;
 use die_on_error::die_on_error;
 mod stdio_ipc;
+mod sweeper;
 mod reconcile_capnp {
     include!(concat!(env!("OUT_DIR"), "/capnp/reconcile_capnp.rs"));
 }
 use async_std::prelude::*;
 use async_std::sync::RwLock;
+use capnp_rpc::rpc_twoparty_capnp::Side;
 use futures::task::LocalSpawn;
 use stdio_ipc::{format_struct, Message};
 
+fn positive_argument<T>(matches: &clap::ArgMatches, name: &str, default: T, error: &str) -> T
+where
+    T: std::str::FromStr + PartialOrd + Default,
+{
+    match matches.value_of(name) {
+        Some(value) => match value.parse::<T>() {
+            Ok(value) if value > T::default() => value,
+            _ => {
+                log::fatal(error);
+                exit(1);
+            }
+        },
+        None => default,
+    }
+}
+
+fn address_ranges(matches: &clap::ArgMatches, name: &str) -> Vec<inbound_policy::Cidr> {
+    match inbound_policy::parse_ranges(matches.values_of(name).into_iter().flatten()) {
+        Some(ranges) => ranges,
+        None => {
+            log::fatal("Address range is invalid");
+            exit(1);
+        }
+    }
+}
+
 fn main() {
     let matches = App::new("Contrasleuth")
         .version("prerelease")
@@ -54,6 +102,138 @@ fn main() {
                 .help("Sets the reverse reconciliation client address")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("batch size")
+                .long("batch-size")
+                .value_name("BATCH_SIZE")
+                .help("Sets the number of messages fetched per batched request")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network id")
+                .long("network-id")
+                .value_name("NETWORK_ID")
+                .help("Sets the network identifier peers must agree on")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("requests per second")
+                .long("max-requests-per-second")
+                .value_name("REQUESTS")
+                .help("Sets the number of requests a peer may make per second")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bytes per second")
+                .long("max-bytes-per-second")
+                .value_name("BYTES")
+                .help("Sets the number of bytes a peer may send per second")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("payload size")
+                .long("max-payload-size")
+                .value_name("BYTES")
+                .help("Sets the largest payload accepted from peers")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("concurrent calls")
+                .long("max-concurrent-calls")
+                .value_name("CALLS")
+                .help("Sets the number of calls a peer may have in flight")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("inventory bytes")
+                .long("max-inventory-bytes")
+                .value_name("BYTES")
+                .help("Sets the total payload size the inventory may hold")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("inventory messages")
+                .long("max-inventory-messages")
+                .value_name("MESSAGES")
+                .help("Sets the number of messages the inventory may hold")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eviction policy")
+                .long("eviction-policy")
+                .value_name("POLICY")
+                .help("Sets which messages are dropped when the inventory is full")
+                .possible_values(&["soonest-expiring", "largest", "lowest-surplus"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory inventory")
+                .long("in-memory-inventory")
+                .help("Keeps messages in memory only, so they are gone after a restart"),
+        )
+        .arg(
+            Arg::with_name("discovery")
+                .long("discovery")
+                .help("Advertises this node and dials nodes found on the local network"),
+        )
+        .arg(
+            Arg::with_name("plaintext")
+                .long("plaintext")
+                .help("Disables transport encryption, only meant for testing"),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .long("allow")
+                .value_name("CIDR")
+                .help("Only accepts incoming connections from this address range, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .value_name("CIDR")
+                .help("Rejects incoming connections from this address range, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("inbound connections")
+                .long("max-inbound-connections")
+                .value_name("CONNECTIONS")
+                .help("Sets the number of incoming connections served at once")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connections per address")
+                .long("max-connections-per-address")
+                .value_name("CONNECTIONS")
+                .help("Sets the number of incoming connections served at once from one IP address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("URL")
+                .help("Tunnels outbound connections through a SOCKS5 proxy, given as socks5://host:port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network key")
+                .long("network-key")
+                .value_name("KEY")
+                .help("Restricts reconciliation to nodes that share this key")
+                .takes_value(true)
+                .conflicts_with("plaintext"),
+        )
+        .arg(
+            Arg::with_name("isolate messages")
+                .long("isolate-messages")
+                .help("Mixes the network key into the proof of work, so messages can't cross into other networks")
+                .requires("network key"),
+        )
         .get_matches();
 
     let database_path = matches.value_of("database").unwrap();
@@ -83,6 +263,108 @@ fn main() {
         None => None,
     };
 
+    let batch_size = positive_argument(&matches, "batch size", 100, "Batch size is invalid");
+
+    let limits = rate_limit::Limits {
+        requests_per_second: positive_argument(
+            &matches,
+            "requests per second",
+            200,
+            "Request rate limit is invalid",
+        ),
+        bytes_per_second: positive_argument(
+            &matches,
+            "bytes per second",
+            4 * 1024 * 1024,
+            "Byte rate limit is invalid",
+        ),
+        max_payload_size: positive_argument(
+            &matches,
+            "payload size",
+            1024 * 1024,
+            "Maximum payload size is invalid",
+        ),
+        max_concurrent_calls: positive_argument(
+            &matches,
+            "concurrent calls",
+            64,
+            "Maximum number of concurrent calls is invalid",
+        ),
+    };
+
+    let network_key = matches.value_of("network key").map(|key| {
+        let mut hasher = Blake2b::new(32);
+        hasher.input(key.as_bytes());
+        let mut network_key = [0u8; 32];
+        hasher.result(&mut network_key);
+        network_key
+    });
+    let proof_of_work_key = if matches.is_present("isolate messages") {
+        network_key
+    } else {
+        None
+    };
+
+    let quota = quota::Quota {
+        max_bytes: if matches.is_present("inventory bytes") {
+            Some(positive_argument(
+                &matches,
+                "inventory bytes",
+                0,
+                "Maximum inventory size is invalid",
+            ))
+        } else {
+            None
+        },
+        max_messages: if matches.is_present("inventory messages") {
+            Some(positive_argument(
+                &matches,
+                "inventory messages",
+                0,
+                "Maximum number of inventory messages is invalid",
+            ))
+        } else {
+            None
+        },
+        eviction_policy: quota::EvictionPolicy::parse(
+            matches
+                .value_of("eviction policy")
+                .unwrap_or("soonest-expiring"),
+        )
+        .unwrap(),
+    };
+
+    let proxy = match matches.value_of("proxy") {
+        Some(url) => match socks5::parse_proxy_url(url) {
+            Some(proxy) => Some(proxy),
+            None => {
+                log::fatal("Proxy URL is invalid");
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let inbound_policy =
+        std::rc::Rc::new(std::cell::RefCell::new(inbound_policy::InboundPolicy::new(
+            address_ranges(&matches, "allow"),
+            address_ranges(&matches, "deny"),
+            positive_argument(
+                &matches,
+                "inbound connections",
+                128,
+                "Maximum number of inbound connections is invalid",
+            ),
+            positive_argument(
+                &matches,
+                "connections per address",
+                4,
+                "Maximum number of connections per address is invalid",
+            ),
+        )));
+
+    let discovery = matches.is_present("discovery");
+
     let manager = SqliteConnectionManager::file(database_path);
 
     let connection = std::sync::Arc::new(match r2d2::Pool::new(manager) {
@@ -93,14 +375,57 @@ fn main() {
         }
     });
 
-    die_on_error(die_on_error(connection.get()).execute(
-        include_str!("../sql/A. Schema/1. Initial schema.sql"),
-        params![],
-    ));
+    if let Err(error) = migrations::migrate(connection.clone()) {
+        log::fatal(error);
+        exit(1);
+    }
+
+    let inventory: inventory::Inventory = if matches.is_present("memory inventory") {
+        std::sync::Arc::new(memory_inventory::MemoryInventory::new(
+            quota,
+            proof_of_work_key,
+        ))
+    } else {
+        std::sync::Arc::new(inventory::SqliteInventory::new(
+            connection.clone(),
+            quota,
+            proof_of_work_key,
+        ))
+    };
+
+    let (learned_peers, learned_peer_receiver) = futures::channel::mpsc::unbounded();
+    let options = std::rc::Rc::new(protocol::SessionOptions {
+        batch_size,
+        network_id: matches
+            .value_of("network id")
+            .unwrap_or(protocol::DEFAULT_NETWORK_ID)
+            .to_owned(),
+        limits,
+        plaintext: matches.is_present("plaintext"),
+        proxy,
+        local_scope: pex::scope(parsed_address.ip()),
+        learned_peers,
+        network_key,
+        proof_of_work_key,
+        inventory,
+        identity: identity::Identity::load_or_generate(connection.clone()),
+        signing_key: signed_envelope::SigningKey::load_or_generate(connection.clone()),
+        active_sessions: Default::default(),
+    });
 
     log::welcome("Welcome to Contrasleuth, a potent communication tool");
     log::welcome("Contrasleuth provides adequate protections for most users. Refer to the guide at https://contrasleuth.cf/warnings to better protect yourself.");
     log::welcome("Standard streams are being used for interprocess communication");
+    log::notice(format!(
+        "This node's identity is {}",
+        base64::encode(&options.identity.public_key)
+    ));
+    if options.plaintext {
+        log::warning("Transport encryption is disabled");
+    }
+    if options.network_key.is_some() {
+        log::notice("Only nodes with the network key are accepted as peers");
+    }
     log::notice(format!(
         "Listening for incoming client connections on {}",
         address
@@ -124,7 +449,12 @@ fn main() {
         mpmc_manual_reset_event::MPMCManualResetEvent::new(),
     ));
 
+    let announcer = std::rc::Rc::new(RwLock::new(announcer::Announcer::new(spawner.clone())));
+
     let reconciliation_intent_clone = reconciliation_intent.clone();
+    let announcer_clone = announcer.clone();
+    let options_clone = options.clone();
+    let inbound_policy_clone = inbound_policy.clone();
     die_on_error(
         spawner.spawn_local_obj(
             Box::new(async move {
@@ -139,22 +469,49 @@ fn main() {
                     }
                 };
                 let mut incoming = listener.incoming();
+                let local_address = die_on_error(listener.local_addr());
                 log::ipc(format_struct(&Message::ServerListenAddress {
-                    address: &die_on_error(listener.local_addr()).to_string(),
+                    address: &local_address.to_string(),
                 }));
+                if discovery {
+                    discovery::start(
+                        local_address,
+                        connection_clone.clone(),
+                        spawner_clone.clone(),
+                        reconciliation_intent_clone.clone(),
+                        announcer_clone.clone(),
+                        options_clone.clone(),
+                    );
+                }
                 let spawner_clone2 = spawner_clone.clone();
                 while let Some(socket) = incoming.next().await {
                     match socket {
                         Ok(socket) => {
+                            let permit = match inbound_policy::admit_socket(
+                                &inbound_policy_clone,
+                                &socket,
+                            ) {
+                                Some(permit) => permit,
+                                None => continue,
+                            };
                             let connection_clone = connection_clone.clone();
                             let reconciliation_intent_clone = reconciliation_intent_clone.clone();
+                            let announcer_clone = announcer_clone.clone();
+                            let options_clone = options_clone.clone();
+                            let spawner_clone3 = spawner_clone2.clone();
                             die_on_error(
                                 spawner_clone2.spawn_local_obj(
                                     Box::new(async move {
-                                        if let Err(error) = reconcile_server::init_server(
+                                        let _permit = permit;
+                                        if let Err(error) = reconcile_client::reconcile(
                                             socket,
+                                            Side::Server,
+                                            None,
                                             connection_clone.clone(),
+                                            spawner_clone3.clone(),
                                             reconciliation_intent_clone.clone(),
+                                            announcer_clone.clone(),
+                                            options_clone.clone(),
                                         )
                                         .await
                                         {
@@ -183,6 +540,9 @@ fn main() {
 
     let spawner_clone = spawner.clone();
     let reconciliation_intent_clone = reconciliation_intent.clone();
+    let announcer_clone = announcer.clone();
+    let options_clone = options.clone();
+    let inbound_policy_clone = inbound_policy.clone();
     if let Some(address) = parsed_reverse_address {
         let connection_clone = connection.clone();
         die_on_error(
@@ -207,17 +567,31 @@ fn main() {
                     while let Some(socket) = incoming.next().await {
                         match socket {
                             Ok(socket) => {
+                                let permit = match inbound_policy::admit_socket(
+                                    &inbound_policy_clone,
+                                    &socket,
+                                ) {
+                                    Some(permit) => permit,
+                                    None => continue,
+                                };
                                 let spawner_clone3 = spawner_clone2.clone();
                                 let connection_clone = connection_clone.clone();
                                 let reconciliation_intent = reconciliation_intent_clone.clone();
+                                let announcer = announcer_clone.clone();
+                                let options = options_clone.clone();
                                 die_on_error(
                                     spawner_clone2.spawn_local_obj(
                                         Box::new(async move {
+                                            let _permit = permit;
                                             if let Err(error) = reconcile_client::reconcile(
                                                 socket,
+                                                Side::Client,
+                                                None,
                                                 connection_clone.clone(),
                                                 spawner_clone3.clone(),
                                                 reconciliation_intent.clone(),
+                                                announcer.clone(),
+                                                options.clone(),
                                             )
                                             .await
                                             {
@@ -245,11 +619,39 @@ fn main() {
         );
     }
 
+    die_on_error(
+        spawner.spawn_local_obj(
+            Box::new(sweeper::run(
+                options.inventory.clone(),
+                reconciliation_intent.clone(),
+            ))
+            .into(),
+        ),
+    );
+
+    let peer_manager = peer_manager::PeerManager::new(
+        connection.clone(),
+        spawner.clone(),
+        reconciliation_intent.clone(),
+        announcer.clone(),
+        options.clone(),
+    );
+
     let spawner_clone = spawner.clone();
     die_on_error(
         spawner.spawn_local_obj(
-            Box::new(async {
-                stdio_ipc::communicate(reconciliation_intent, connection, spawner_clone).await;
+            Box::new(async move {
+                peer_manager.start(learned_peer_receiver).await;
+                stdio_ipc::communicate(
+                    reconciliation_intent,
+                    announcer,
+                    connection,
+                    spawner_clone,
+                    options,
+                    peer_manager,
+                    inbound_policy,
+                )
+                .await;
             })
             .into(),
         ),
@@ -0,0 +1,248 @@
+use crate::die_on_error::die_on_error;
+use crate::inventory::{now, Insertion, InventoryStore, Message, PeerCursor};
+use crate::message_hash::message_hash;
+use crate::proof_of_work::surplus;
+use crate::quota::{EvictionPolicy, Quota};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+struct Stored {
+    message: Message,
+    sequence: i64,
+    surplus: f64,
+}
+
+impl Stored {
+    fn size(&self) -> u64 {
+        self.message.payload.len() as u64
+    }
+
+    fn is_live(&self, now: i64) -> bool {
+        self.message.expiration_time > now
+    }
+}
+
+#[derive(Default)]
+struct State {
+    messages: BTreeMap<Vec<u8>, Stored>,
+    by_sequence: BTreeMap<i64, Vec<u8>>,
+    insertion_counter: i64,
+    bytes: u64,
+    peer_cursors: HashMap<Vec<u8>, PeerCursor>,
+}
+
+impl State {
+    fn remove(&mut self, hash: &[u8]) {
+        if let Some(stored) = self.messages.remove(hash) {
+            self.by_sequence.remove(&stored.sequence);
+            self.bytes -= stored.size();
+        }
+    }
+}
+
+/// Keeps messages in memory only, for ephemeral nodes and tests. Everything
+/// is lost when the process exits.
+pub struct MemoryInventory {
+    state: Mutex<State>,
+    quota: Quota,
+    proof_of_work_key: Option<[u8; 32]>,
+}
+
+impl MemoryInventory {
+    pub fn new(quota: Quota, proof_of_work_key: Option<[u8; 32]>) -> MemoryInventory {
+        MemoryInventory {
+            state: Mutex::new(State::default()),
+            quota,
+            proof_of_work_key,
+        }
+    }
+
+    /// Same policy as the SQLite store: only messages ranked below the
+    /// incoming one are evicted, and nothing is if that isn't enough.
+    fn make_room(&self, state: &mut State, incoming: &Stored) -> bool {
+        let max_bytes = self.quota.max_bytes.unwrap_or(u64::MAX);
+        let max_messages = self.quota.max_messages.unwrap_or(u64::MAX);
+        if incoming.size() > max_bytes {
+            return false;
+        }
+        let fits = |count: u64, bytes: u64| {
+            count < max_messages && bytes.saturating_add(incoming.size()) <= max_bytes
+        };
+        let mut count = state.messages.len() as u64;
+        let mut bytes = state.bytes;
+        if fits(count, bytes) {
+            return true;
+        }
+        let mut candidates: Vec<(&Vec<u8>, &Stored)> = state
+            .messages
+            .iter()
+            .filter(|(_, stored)| match self.quota.eviction_policy {
+                EvictionPolicy::SoonestExpiring => {
+                    stored.message.expiration_time < incoming.message.expiration_time
+                }
+                EvictionPolicy::Largest => stored.size() > incoming.size(),
+                EvictionPolicy::LowestSurplus => stored.surplus < incoming.surplus,
+            })
+            .collect();
+        match self.quota.eviction_policy {
+            EvictionPolicy::SoonestExpiring => {
+                candidates.sort_by_key(|(_, stored)| stored.message.expiration_time)
+            }
+            EvictionPolicy::Largest => {
+                candidates.sort_by_key(|(_, stored)| std::cmp::Reverse(stored.size()))
+            }
+            EvictionPolicy::LowestSurplus => candidates.sort_by(|(_, a), (_, b)| {
+                a.surplus
+                    .partial_cmp(&b.surplus)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            }),
+        }
+        let mut evicted = Vec::new();
+        for (hash, stored) in candidates {
+            if fits(count, bytes) {
+                break;
+            }
+            evicted.push(hash.clone());
+            count -= 1;
+            bytes -= stored.size();
+        }
+        if !fits(count, bytes) {
+            return false;
+        }
+        for hash in evicted {
+            state.remove(&hash);
+        }
+        true
+    }
+}
+
+impl InventoryStore for MemoryInventory {
+    fn exists(&self, hash: &[u8]) -> bool {
+        die_on_error(self.state.lock())
+            .messages
+            .get(hash)
+            .is_some_and(|stored| stored.is_live(now()))
+    }
+
+    fn retrieve(&self, hash: &[u8]) -> Option<Message> {
+        let state = die_on_error(self.state.lock());
+        state
+            .messages
+            .get(hash)
+            .filter(|stored| stored.is_live(now()))
+            .map(|stored| stored.message.clone())
+    }
+
+    fn insert_many(&self, messages: &[Message]) -> Vec<Insertion> {
+        let mut state = die_on_error(self.state.lock());
+        let mut outcomes = Vec::new();
+        for message in messages {
+            let hash = message_hash(&message.payload, message.expiration_time).to_vec();
+            if state.messages.contains_key(&hash) {
+                outcomes.push(Insertion::Duplicate);
+                continue;
+            }
+            let stored = Stored {
+                message: message.clone(),
+                sequence: state.insertion_counter + 1,
+                surplus: surplus(
+                    &message.payload,
+                    message.nonce,
+                    message.expiration_time,
+                    self.proof_of_work_key.as_ref().map(|key| &key[..]),
+                ),
+            };
+            if !self.make_room(&mut state, &stored) {
+                outcomes.push(Insertion::Full);
+                continue;
+            }
+            state.insertion_counter += 1;
+            state.bytes += stored.size();
+            state.by_sequence.insert(stored.sequence, hash.clone());
+            state.messages.insert(hash, stored);
+            outcomes.push(Insertion::Inserted);
+        }
+        outcomes
+    }
+
+    fn hashes(&self) -> Vec<Vec<u8>> {
+        let now = now();
+        let state = die_on_error(self.state.lock());
+        state
+            .messages
+            .iter()
+            .filter(|(_, stored)| stored.is_live(now))
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    fn hashes_in_range(&self, lower_bound: &[u8], upper_bound: &[u8]) -> Vec<Vec<u8>> {
+        if lower_bound >= upper_bound {
+            return Vec::new();
+        }
+        let now = now();
+        let state = die_on_error(self.state.lock());
+        state
+            .messages
+            .range(lower_bound.to_vec()..upper_bound.to_vec())
+            .filter(|(_, stored)| stored.is_live(now))
+            .map(|(hash, _)| hash.clone())
+            .collect()
+    }
+
+    fn hashes_since(&self, cursor: i64) -> (Vec<Vec<u8>>, i64) {
+        let now = now();
+        let state = die_on_error(self.state.lock());
+        let next_cursor = state.insertion_counter;
+        if cursor >= next_cursor {
+            return (Vec::new(), next_cursor);
+        }
+        let hashes = state
+            .by_sequence
+            .range(cursor + 1..=next_cursor)
+            .filter(|(_, hash)| state.messages[*hash].is_live(now))
+            .map(|(_, hash)| hash.clone())
+            .collect();
+        (hashes, next_cursor)
+    }
+
+    fn unsequenced_hashes(&self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+
+    fn peer_cursor(&self, public_key: &[u8]) -> Option<PeerCursor> {
+        die_on_error(self.state.lock())
+            .peer_cursors
+            .get(public_key)
+            .copied()
+    }
+
+    fn set_peer_cursor(&self, public_key: &[u8], cursor: PeerCursor) {
+        die_on_error(self.state.lock())
+            .peer_cursors
+            .insert(public_key.to_vec(), cursor);
+    }
+
+    fn purge_expired(&self) -> Vec<Vec<u8>> {
+        let now = now();
+        let mut state = die_on_error(self.state.lock());
+        let expired: Vec<Vec<u8>> = state
+            .messages
+            .iter()
+            .filter(|(_, stored)| !stored.is_live(now))
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in &expired {
+            state.remove(hash);
+        }
+        expired
+    }
+
+    fn next_expiration_time(&self) -> Option<i64> {
+        die_on_error(self.state.lock())
+            .messages
+            .values()
+            .map(|stored| stored.message.expiration_time)
+            .min()
+    }
+}
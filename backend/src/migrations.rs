@@ -0,0 +1,197 @@
+use rusqlite::{params, Connection};
+
+type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
+
+macro_rules! migration {
+    ($name:expr) => {
+        (
+            $name,
+            include_str!(concat!("../sql/A. Schema/", $name, ".sql")),
+        )
+    };
+}
+
+/// Applied in order. The database's `user_version` is the number of
+/// migrations it has, so new ones are only ever appended.
+const MIGRATIONS: &[(&str, &str)] = &[
+    migration!("1. Initial schema"),
+    migration!("2. Add insertion sequence"),
+    migration!("3. Insertion sequence index"),
+    migration!("4. Insertion counter"),
+    migration!("5. Initialize insertion counter"),
+    migration!("6. Peer cursors"),
+    migration!("7. Peers"),
+    migration!("8. Identity"),
+    migration!("9. Recipient keys"),
+    migration!("10. Ratchet sessions"),
+    migration!("11. Eviction columns"),
+    migration!("12. Expiration time index"),
+    migration!("13. Peer cursors by node"),
+    migration!("14. Envelope cursor"),
+    migration!("15. Learned peers"),
+    migration!("16. Signing key"),
+    migration!("17. Unsequenced envelope scan"),
+    migration!("18. Accepted prekeys"),
+];
+
+fn version(connection: &Connection) -> rusqlite::Result<usize> {
+    let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(version as usize)
+}
+
+/// Databases created before migrations were tracked have a version of 0.
+/// They hold at most the schema of the first ten migrations, which tolerate
+/// being applied again, except for adding the insertion sequence. Later ones,
+/// such as those adding or dropping columns, only run once.
+fn baseline(connection: &Connection) -> rusqlite::Result<usize> {
+    let sequence_exists: i64 = connection.query_row(
+        include_str!("../sql/G. Migrations/1. Insertion sequence exists.sql"),
+        params![],
+        |row| row.get(0),
+    )?;
+    Ok(if sequence_exists == 0 { 0 } else { 2 })
+}
+
+fn apply(connection: &mut Connection, script: &str, version: usize) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(script)?;
+    transaction.pragma_update(None, "user_version", &(version as i64))?;
+    transaction.commit()
+}
+
+/// Brings the schema up to date, one transaction per migration, so a
+/// failure leaves the database at the last version that applied cleanly.
+pub fn migrate(pool: Pool) -> Result<(), String> {
+    let mut connection = pool
+        .get()
+        .map_err(|error| format!("Can't open the database: {:?}", error))?;
+    let mut current = version(&connection)
+        .map_err(|error| format!("Can't read the schema version: {:?}", error))?;
+    if current > MIGRATIONS.len() {
+        return Err(format!(
+            "The database has schema version {}, but this build only knows up to {}",
+            current,
+            MIGRATIONS.len()
+        ));
+    }
+    if current == 0 {
+        current = baseline(&connection)
+            .map_err(|error| format!("Can't inspect the existing schema: {:?}", error))?;
+    }
+    for (index, (name, script)) in MIGRATIONS.iter().enumerate().skip(current) {
+        apply(&mut connection, script, index + 1).map_err(|error| {
+            format!(
+                "Migration {} failed with error {:?}. The database was left at schema version {}",
+                name, error, index
+            )
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    fn pool() -> Pool {
+        std::sync::Arc::new(
+            r2d2::Pool::builder()
+                .max_size(1)
+                .build(SqliteConnectionManager::memory())
+                .unwrap(),
+        )
+    }
+
+    fn table_exists(connection: &Connection, name: &str) -> bool {
+        connection
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+                params![name],
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+            == 1
+    }
+
+    fn legacy_database(scripts: &[(&str, &str)]) -> Pool {
+        let pool = pool();
+        let connection = pool.get().unwrap();
+        for (_, script) in scripts {
+            connection.execute_batch(script).unwrap();
+        }
+        connection
+            .execute(
+                "INSERT INTO inventory (blake2b, payload, nonce, expiration_time) VALUES (?, ?, ?, ?)",
+                params![vec![1u8; 64], vec![2u8; 16], 3, 4],
+            )
+            .unwrap();
+        assert_eq!(version(&connection).unwrap(), 0);
+        drop(connection);
+        pool
+    }
+
+    fn assert_migrated_with_message(pool: &Pool) {
+        let connection = pool.get().unwrap();
+        assert_eq!(version(&connection).unwrap(), MIGRATIONS.len());
+        let payload: Vec<u8> = connection
+            .query_row(
+                "SELECT payload FROM inventory WHERE blake2b = ?",
+                params![vec![1u8; 64]],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(payload, vec![2u8; 16]);
+    }
+
+    #[test]
+    fn fresh_databases_are_fully_migrated() {
+        let pool = pool();
+        migrate(pool.clone()).unwrap();
+        assert_eq!(version(&pool.get().unwrap()).unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrating_twice_is_a_no_op() {
+        let pool = pool();
+        migrate(pool.clone()).unwrap();
+        migrate(pool.clone()).unwrap();
+        assert_eq!(version(&pool.get().unwrap()).unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn untracked_databases_are_migrated() {
+        let pool = legacy_database(&MIGRATIONS[..1]);
+        migrate(pool.clone()).unwrap();
+        assert_migrated_with_message(&pool);
+    }
+
+    #[test]
+    fn untracked_databases_with_a_sequence_are_migrated() {
+        let pool = legacy_database(&MIGRATIONS[..2]);
+        migrate(pool.clone()).unwrap();
+        assert_migrated_with_message(&pool);
+    }
+
+    #[test]
+    fn newer_databases_are_refused() {
+        let pool = pool();
+        pool.get()
+            .unwrap()
+            .pragma_update(None, "user_version", &(MIGRATIONS.len() as i64 + 1))
+            .unwrap();
+        assert!(migrate(pool).is_err());
+    }
+
+    #[test]
+    fn failed_migrations_are_rolled_back() {
+        let pool = pool();
+        migrate(pool.clone()).unwrap();
+        let mut connection = pool.get().unwrap();
+        let script = "CREATE TABLE partial (value INTEGER); INSERT INTO missing VALUES (1)";
+        assert!(apply(&mut connection, script, MIGRATIONS.len() + 1).is_err());
+        assert_eq!(version(&connection).unwrap(), MIGRATIONS.len());
+        assert!(!table_exists(&connection, "partial"));
+        assert!(table_exists(&connection, "inventory"));
+    }
+}
@@ -0,0 +1,399 @@
+use crate::identity::Identity;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use snow::{HandshakeState, TransportState};
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Used in private networks. Nodes without the network key fail the
+/// handshake before the RPC system starts.
+const PSK_PATTERN: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+
+/// Noise messages can't be longer than this.
+const MAX_MESSAGE_LENGTH: usize = 65535;
+
+const TAG_LENGTH: usize = 16;
+
+const MAX_PLAINTEXT_LENGTH: usize = MAX_MESSAGE_LENGTH - TAG_LENGTH;
+
+/// Peers that don't finish the handshake by then are dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn noise_error(error: snow::Error) -> capnp::Error {
+    capnp::Error::failed(format!("Noise handshake failed: {:?}", error))
+}
+
+fn invalid_data<E: std::fmt::Debug>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", error))
+}
+
+/// Every message, during and after the handshake, is preceded by its length
+/// as a big-endian 16-bit integer.
+async fn send_frame<S: AsyncWrite + Unpin>(stream: &mut S, message: &[u8]) -> std::io::Result<()> {
+    stream
+        .write_all(&(message.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(message).await?;
+    stream.flush().await
+}
+
+async fn receive_frame<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Vec<u8>> {
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length).await?;
+    let mut message = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+async fn write_handshake<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    state: &mut HandshakeState,
+) -> Result<(), capnp::Error> {
+    let mut message = vec![0u8; MAX_MESSAGE_LENGTH];
+    let length = state
+        .write_message(&[], &mut message)
+        .map_err(noise_error)?;
+    send_frame(stream, &message[..length]).await?;
+    Ok(())
+}
+
+async fn read_handshake<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    state: &mut HandshakeState,
+) -> Result<(), capnp::Error> {
+    let message = receive_frame(stream).await?;
+    let mut payload = vec![0u8; MAX_MESSAGE_LENGTH];
+    state
+        .read_message(&message, &mut payload)
+        .map_err(noise_error)?;
+    Ok(())
+}
+
+/// Runs the XX handshake, with the client end of the two-party network as
+/// the initiator. Returns the transport and the handshake hash, which the
+/// hello signatures cover so identities can't be relayed across connections.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    side: Side,
+    identity: &Identity,
+    network_key: Option<[u8; 32]>,
+) -> Result<(Transport, Vec<u8>), capnp::Error> {
+    let private_key = identity.noise_private_key();
+    let pattern = match network_key {
+        Some(_) => PSK_PATTERN,
+        None => PATTERN,
+    };
+    let builder =
+        snow::Builder::new(pattern.parse().map_err(noise_error)?).local_private_key(&private_key);
+    let builder = match &network_key {
+        Some(network_key) => builder.psk(3, network_key),
+        None => builder,
+    };
+    let mut state = match side {
+        Side::Client => builder.build_initiator(),
+        Side::Server => builder.build_responder(),
+    }
+    .map_err(noise_error)?;
+
+    let exchange = async {
+        match side {
+            Side::Client => {
+                write_handshake(stream, &mut state).await?;
+                read_handshake(stream, &mut state).await?;
+                write_handshake(stream, &mut state).await?;
+            }
+            Side::Server => {
+                read_handshake(stream, &mut state).await?;
+                write_handshake(stream, &mut state).await?;
+                read_handshake(stream, &mut state).await?;
+            }
+        }
+        Ok::<(), capnp::Error>(())
+    };
+    match async_std::future::timeout(HANDSHAKE_TIMEOUT, exchange).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(capnp::Error::disconnected(
+                "Noise handshake timed out".to_owned(),
+            ))
+        }
+    }
+
+    let handshake_hash = state.get_handshake_hash().to_vec();
+    let state = state.into_transport_mode().map_err(noise_error)?;
+    Ok((
+        Transport {
+            state: Rc::new(RefCell::new(state)),
+        },
+        handshake_hash,
+    ))
+}
+
+/// Cipher state shared by both halves of a connection.
+pub struct Transport {
+    state: Rc<RefCell<TransportState>>,
+}
+
+impl Transport {
+    pub fn reader<R>(&self, inner: R) -> NoiseReader<R> {
+        NoiseReader {
+            inner,
+            state: self.state.clone(),
+            frame: Vec::new(),
+            plaintext: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn writer<W>(&self, inner: W) -> NoiseWriter<W> {
+        NoiseWriter {
+            inner,
+            state: self.state.clone(),
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+/// Decrypts the frames read from `inner`.
+pub struct NoiseReader<R> {
+    inner: R,
+    state: Rc<RefCell<TransportState>>,
+    /// The frame read so far, including its length.
+    frame: Vec<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+}
+
+impl<R: AsyncRead + Unpin> NoiseReader<R> {
+    fn poll_fill(&mut self, context: &mut Context, wanted: usize) -> Poll<std::io::Result<bool>> {
+        while self.frame.len() < wanted {
+            let mut buffer = vec![0u8; wanted - self.frame.len()];
+            match Pin::new(&mut self.inner).poll_read(context, &mut buffer) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(false)),
+                Poll::Ready(Ok(read)) => self.frame.extend_from_slice(&buffer[..read]),
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(true))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for NoiseReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context,
+        buffer: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        while this.position == this.plaintext.len() {
+            match this.poll_fill(context, 2) {
+                Poll::Ready(Ok(true)) => {}
+                Poll::Ready(Ok(false)) if this.frame.is_empty() => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(false)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()))
+                }
+                other => return other.map(|result| result.map(|_| 0)),
+            }
+            let length = u16::from_be_bytes([this.frame[0], this.frame[1]]) as usize;
+            match this.poll_fill(context, 2 + length) {
+                Poll::Ready(Ok(true)) => {}
+                Poll::Ready(Ok(false)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()))
+                }
+                other => return other.map(|result| result.map(|_| 0)),
+            }
+            let mut plaintext = vec![0u8; length];
+            let length = this
+                .state
+                .borrow_mut()
+                .read_message(&this.frame[2..], &mut plaintext)
+                .map_err(invalid_data)?;
+            plaintext.truncate(length);
+            this.frame.clear();
+            this.plaintext = plaintext;
+            this.position = 0;
+        }
+        let length = buffer.len().min(this.plaintext.len() - this.position);
+        buffer[..length].copy_from_slice(&this.plaintext[this.position..this.position + length]);
+        this.position += length;
+        Poll::Ready(Ok(length))
+    }
+}
+
+/// Encrypts whatever is written into frames on `inner`. A frame is accepted
+/// whole and sent out before the next one.
+pub struct NoiseWriter<W> {
+    inner: W,
+    state: Rc<RefCell<TransportState>>,
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl<W: AsyncWrite + Unpin> NoiseWriter<W> {
+    fn poll_drain(&mut self, context: &mut Context) -> Poll<std::io::Result<()>> {
+        while self.position < self.pending.len() {
+            match Pin::new(&mut self.inner).poll_write(context, &self.pending[self.position..]) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()))
+                }
+                Poll::Ready(Ok(written)) => self.position += written,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.pending.clear();
+        self.position = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for NoiseWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut Context,
+        buffer: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        if let Poll::Ready(Err(error)) = this.poll_drain(context) {
+            return Poll::Ready(Err(error));
+        }
+        if !this.pending.is_empty() {
+            return Poll::Pending;
+        }
+        let length = buffer.len().min(MAX_PLAINTEXT_LENGTH);
+        let mut frame = vec![0u8; 2 + length + TAG_LENGTH];
+        let encrypted = this
+            .state
+            .borrow_mut()
+            .write_message(&buffer[..length], &mut frame[2..])
+            .map_err(invalid_data)?;
+        frame[..2].copy_from_slice(&(encrypted as u16).to_be_bytes());
+        frame.truncate(2 + encrypted);
+        this.pending = frame;
+        // Errors surface on the next write or flush.
+        let _ = this.poll_drain(context);
+        Poll::Ready(Ok(length))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_drain(context) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(context),
+            other => other,
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_drain(context) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_close(context),
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+
+    fn transport(state: HandshakeState) -> Transport {
+        Transport {
+            state: Rc::new(RefCell::new(state.into_transport_mode().unwrap())),
+        }
+    }
+
+    fn transports() -> (Transport, Transport) {
+        let initiator_key = snow::Builder::new(PATTERN.parse().unwrap())
+            .generate_keypair()
+            .unwrap()
+            .private;
+        let responder_key = snow::Builder::new(PATTERN.parse().unwrap())
+            .generate_keypair()
+            .unwrap()
+            .private;
+        let mut initiator = snow::Builder::new(PATTERN.parse().unwrap())
+            .local_private_key(&initiator_key)
+            .build_initiator()
+            .unwrap();
+        let mut responder = snow::Builder::new(PATTERN.parse().unwrap())
+            .local_private_key(&responder_key)
+            .build_responder()
+            .unwrap();
+        let mut message = vec![0u8; MAX_MESSAGE_LENGTH];
+        let mut payload = vec![0u8; MAX_MESSAGE_LENGTH];
+        for _ in 0..3 {
+            let (writer, reader) = if initiator.is_my_turn() {
+                (&mut initiator, &mut responder)
+            } else {
+                (&mut responder, &mut initiator)
+            };
+            let length = writer.write_message(&[], &mut message).unwrap();
+            reader
+                .read_message(&message[..length], &mut payload)
+                .unwrap();
+        }
+        (transport(initiator), transport(responder))
+    }
+
+    /// Writes `plaintext` and returns the lengths of the frames it took, after
+    /// checking that it reads back whole.
+    fn frame_lengths(plaintext: &[u8]) -> Vec<usize> {
+        let (sender, receiver) = transports();
+        let mut writer = sender.writer(Cursor::new(Vec::new()));
+        block_on(writer.write_all(plaintext)).unwrap();
+        block_on(writer.flush()).unwrap();
+        let frames = writer.inner.into_inner();
+
+        let mut lengths = Vec::new();
+        let mut rest = &frames[..];
+        while !rest.is_empty() {
+            let length = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            lengths.push(length);
+            rest = &rest[2 + length..];
+        }
+
+        let mut reader = receiver.reader(Cursor::new(frames));
+        let mut received = Vec::new();
+        block_on(reader.read_to_end(&mut received)).unwrap();
+        assert!(received == plaintext);
+        lengths
+    }
+
+    #[test]
+    fn writes_are_split_at_the_largest_frame() {
+        assert_eq!(frame_lengths(b"hello"), [5 + TAG_LENGTH]);
+        assert_eq!(
+            frame_lengths(&vec![1u8; MAX_PLAINTEXT_LENGTH]),
+            [MAX_MESSAGE_LENGTH]
+        );
+        assert_eq!(
+            frame_lengths(&vec![2u8; MAX_PLAINTEXT_LENGTH + 1]),
+            [MAX_MESSAGE_LENGTH, 1 + TAG_LENGTH]
+        );
+        assert_eq!(
+            frame_lengths(&vec![3u8; 2 * MAX_PLAINTEXT_LENGTH + 100]),
+            [MAX_MESSAGE_LENGTH, MAX_MESSAGE_LENGTH, 100 + TAG_LENGTH]
+        );
+    }
+
+    #[test]
+    fn truncated_frames_are_an_error() {
+        let (sender, receiver) = transports();
+        let mut writer = sender.writer(Cursor::new(Vec::new()));
+        block_on(writer.write_all(&vec![4u8; MAX_PLAINTEXT_LENGTH])).unwrap();
+        let mut frames = writer.inner.into_inner();
+        frames.pop();
+        let mut reader = receiver.reader(Cursor::new(frames));
+        let error = block_on(reader.read_to_end(&mut Vec::new())).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::announcer::Announcer;
+use crate::connect::connect;
+use crate::die_on_error::die_on_error;
+use crate::learned_peers::{self, LearnedPeer};
+use crate::log;
+use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::peers::{self, Peer};
+use crate::pex;
+use crate::protocol::SessionOptions;
+use async_std::sync::RwLock;
+use async_std::task;
+use futures::channel::mpsc;
+use futures::executor::LocalSpawner;
+use futures::task::LocalSpawn;
+use futures::StreamExt;
+use r2d2_sqlite::SqliteConnectionManager;
+use rand::Rng;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Delay before redialing a peer whose last session went fine.
+const BASE_DELAY: Duration = Duration::from_secs(5);
+
+/// Sessions that end with an error sooner than this count as failures.
+const MIN_SESSION_DURATION: Duration = Duration::from_secs(60);
+
+/// Upper bound on the delay between two attempts to dial a peer.
+const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+type Pool = std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>;
+
+/// Keeps connections to the peers in the `peers` table, redialing them with
+/// exponential backoff when they fail. Learned peers are dialed the same way,
+/// as soon as they are learned, until they expire.
+#[derive(Clone)]
+pub struct PeerManager {
+    connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
+    spawner: LocalSpawner,
+    reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+    announcer: std::rc::Rc<RwLock<Announcer>>,
+    options: std::rc::Rc<SessionOptions>,
+    dialing: std::rc::Rc<RefCell<HashSet<String>>>,
+}
+
+/// Doubles the delay for every consecutive failure, and picks a random point
+/// in its upper half so peers that failed together don't redial together.
+fn backoff(failure_count: i64) -> Duration {
+    let mut delay = BASE_DELAY;
+    for _ in 0..failure_count {
+        delay *= 2;
+        if delay >= MAX_DELAY {
+            delay = MAX_DELAY;
+            break;
+        }
+    }
+    let millis = delay.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(millis / 2, millis + 1))
+}
+
+/// Returns the failure count of the peer, and whether it was learned rather
+/// than added. Peers that were removed or forgotten aren't found.
+fn lookup(connection: Pool, address: &str) -> Option<(i64, bool)> {
+    match peers::retrieve(connection.clone(), address) {
+        Some(peer) => Some((peer.failure_count, false)),
+        None => learned_peers::retrieve(connection, address).map(|peer| (peer.failure_count, true)),
+    }
+}
+
+impl PeerManager {
+    pub fn new(
+        connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
+        spawner: LocalSpawner,
+        reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+        announcer: std::rc::Rc<RwLock<Announcer>>,
+        options: std::rc::Rc<SessionOptions>,
+    ) -> PeerManager {
+        PeerManager {
+            connection,
+            spawner,
+            reconciliation_intent,
+            announcer,
+            options,
+            dialing: std::rc::Rc::new(RefCell::new(HashSet::new())),
+        }
+    }
+
+    /// Starts dialing every known peer, and every peer learned from now on.
+    pub async fn start(&self, mut newly_learned: mpsc::UnboundedReceiver<String>) {
+        let connection = self.connection.clone();
+        for peer in task::spawn(async move { peers::list(connection) }).await {
+            self.dial(peer.address);
+        }
+        let connection = self.connection.clone();
+        let learned = task::spawn(async move {
+            learned_peers::expire(
+                connection.clone(),
+                pex::LEARNED_PEER_LIFETIME,
+                pex::MAX_FAILURES,
+            );
+            learned_peers::list(connection)
+        })
+        .await;
+        for peer in learned {
+            self.dial(peer.address);
+        }
+        let manager = self.clone();
+        die_on_error(
+            self.spawner.spawn_local_obj(
+                Box::new(async move {
+                    while let Some(address) = newly_learned.next().await {
+                        manager.dial(address);
+                    }
+                })
+                .into(),
+            ),
+        );
+    }
+
+    /// Learned peers that are added stop being learned ones.
+    pub async fn add(&self, address: String, shared: bool) {
+        let connection = self.connection.clone();
+        let address1 = address.clone();
+        task::spawn(async move {
+            peers::insert(connection.clone(), &address1, shared);
+            learned_peers::remove(connection, &address1);
+        })
+        .await;
+        self.dial(address);
+    }
+
+    /// Stops redialing the peer, whether it was added or learned. A session that is already running is left
+    /// alone until it ends.
+    pub async fn remove(&self, address: String) {
+        let connection = self.connection.clone();
+        task::spawn(async move {
+            peers::remove(connection.clone(), &address);
+            learned_peers::remove(connection, &address);
+        })
+        .await;
+    }
+
+    pub async fn list(&self) -> Vec<Peer> {
+        let connection = self.connection.clone();
+        task::spawn(async move { peers::list(connection) }).await
+    }
+
+    pub async fn list_learned(&self) -> Vec<LearnedPeer> {
+        let connection = self.connection.clone();
+        task::spawn(async move { learned_peers::list(connection) }).await
+    }
+
+    fn dial(&self, address: String) {
+        if !self.dialing.borrow_mut().insert(address.clone()) {
+            return;
+        }
+        let manager = self.clone();
+        die_on_error(
+            self.spawner.spawn_local_obj(
+                Box::new(async move {
+                    manager.redial(&address).await;
+                    manager.dialing.borrow_mut().remove(&address);
+                })
+                .into(),
+            ),
+        );
+    }
+
+    async fn redial(&self, address: &str) {
+        let mut first_attempt = true;
+        loop {
+            let connection = self.connection.clone();
+            let address1 = address.to_owned();
+            let (failure_count, learned) =
+                match task::spawn(async move { lookup(connection, &address1) }).await {
+                    Some(peer) => peer,
+                    None => return,
+                };
+            if learned && failure_count >= pex::MAX_FAILURES {
+                log::notice(format!("Forgetting learned peer {}", address));
+                let connection = self.connection.clone();
+                let address1 = address.to_owned();
+                task::spawn(async move { learned_peers::remove(connection, &address1) }).await;
+                return;
+            }
+            if !first_attempt {
+                let delay = backoff(failure_count);
+                log::notice(format!(
+                    "Redialing {} in {} seconds",
+                    address,
+                    delay.as_secs()
+                ));
+                task::sleep(delay).await;
+            }
+            first_attempt = false;
+
+            let (sender, mut receiver) = mpsc::unbounded();
+            let sender1 = sender.clone();
+            let address1 = address.to_owned();
+            let address2 = address.to_owned();
+            let started = Instant::now();
+            connect(
+                address.to_owned(),
+                self.options.proxy.clone(),
+                self.connection.clone(),
+                self.spawner.clone(),
+                self.reconciliation_intent.clone(),
+                self.announcer.clone(),
+                self.options.clone(),
+                move |error| {
+                    log::warning(format!(
+                        "Can't connect to {} due to error {:?}",
+                        address1, error
+                    ));
+                    let _ = sender.unbounded_send(false);
+                },
+                move |result| {
+                    // Sessions that fail right away, like those refused
+                    // during the handshake, count as failures.
+                    let succeeded = match result {
+                        Ok(()) => true,
+                        Err(error) => {
+                            log::warning(format!(
+                                "Error occurred while reconciling with {} due to error {:?}",
+                                address2, error
+                            ));
+                            started.elapsed() >= MIN_SESSION_DURATION
+                        }
+                    };
+                    let _ = sender1.unbounded_send(succeeded);
+                },
+            );
+            let succeeded = receiver.next().await.unwrap_or(false);
+            let connection = self.connection.clone();
+            let address1 = address.to_owned();
+            task::spawn(async move {
+                match (learned, succeeded) {
+                    (false, true) => peers::record_success(connection, &address1),
+                    (false, false) => peers::record_failure(connection, &address1),
+                    (true, true) => learned_peers::record_success(connection, &address1),
+                    (true, false) => learned_peers::record_failure(connection, &address1),
+                }
+            })
+            .await;
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::die_on_error::die_on_error;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
+
+/// Timestamps are in seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Peer {
+    pub address: String,
+    pub last_success: Option<i64>,
+    pub last_failure: Option<i64>,
+    pub failure_count: i64,
+    /// Shared peers are handed out to other nodes through peer exchange.
+    pub shared: bool,
+}
+
+fn read_peer(row: &rusqlite::Row) -> Peer {
+    Peer {
+        address: die_on_error(row.get(0)),
+        last_success: die_on_error(row.get(1)),
+        last_failure: die_on_error(row.get(2)),
+        failure_count: die_on_error(row.get(3)),
+        shared: die_on_error(row.get(4)),
+    }
+}
+
+/// Known peers keep their history, but whether they are shared is updated.
+pub fn insert(pool: Pool, address: &str, shared: bool) {
+    let connection = die_on_error(pool.get());
+    die_on_error(connection.execute(
+        include_str!("../sql/C. Peers/1. Put peer.sql"),
+        params![address, shared],
+    ));
+    die_on_error(connection.execute(
+        include_str!("../sql/C. Peers/8. Set shared.sql"),
+        params![shared, address],
+    ));
+}
+
+pub fn remove(pool: Pool, address: &str) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/C. Peers/2. Remove peer.sql"),
+        params![address],
+    ));
+}
+
+pub fn list(pool: Pool) -> Vec<Peer> {
+    let connection = die_on_error(pool.get());
+    let mut statement =
+        die_on_error(connection.prepare(include_str!("../sql/C. Peers/3. Retrieve peers.sql")));
+    let mut rows = die_on_error(statement.query(params![]));
+    let mut peers = Vec::new();
+    while let Some(row) = die_on_error(rows.next()) {
+        peers.push(read_peer(row));
+    }
+    peers
+}
+
+pub fn retrieve(pool: Pool, address: &str) -> Option<Peer> {
+    let connection = die_on_error(pool.get());
+    let mut statement =
+        die_on_error(connection.prepare(include_str!("../sql/C. Peers/4. Retrieve peer.sql")));
+    let mut rows = die_on_error(statement.query(params![address]));
+    die_on_error(rows.next()).map(read_peer)
+}
+
+pub fn record_success(pool: Pool, address: &str) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/C. Peers/5. Record success.sql"),
+        params![address],
+    ));
+}
+
+pub fn record_failure(pool: Pool, address: &str) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/C. Peers/6. Record failure.sql"),
+        params![address],
+    ));
+}
+
+/// Picks random peers we've had a successful session with, among shared
+/// and learned ones.
+pub fn sample(pool: Pool, count: u32) -> Vec<String> {
+    let connection = die_on_error(pool.get());
+    let mut statement =
+        die_on_error(connection.prepare(include_str!("../sql/C. Peers/7. Sample peers.sql")));
+    let mut rows = die_on_error(statement.query(params![count]));
+    let mut addresses = Vec::new();
+    while let Some(row) = die_on_error(rows.next()) {
+        addresses.push(die_on_error(row.get(0)));
+    }
+    addresses
+}
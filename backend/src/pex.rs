@@ -0,0 +1,240 @@
+use crate::learned_peers;
+use crate::peers;
+use std::net::{IpAddr, SocketAddr};
+
+type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
+
+/// Number of addresses served per `peers` call. Longer lists from peers are
+/// truncated.
+pub const SAMPLE_SIZE: u32 = 16;
+
+/// Number of addresses learned from a single session.
+pub const MAX_LEARNED_PER_SESSION: usize = 8;
+
+/// Number of learned addresses kept from a single source.
+pub const MAX_LEARNED_PER_SOURCE: i64 = 32;
+
+/// Addresses are no longer learned once this many are kept.
+pub const MAX_LEARNED_PEERS: i64 = 256;
+
+/// Learned addresses are forgotten once no peer has mentioned them, and we
+/// haven't reached them, for this many seconds.
+pub const LEARNED_PEER_LIFETIME: i64 = 7 * 24 * 60 * 60;
+
+/// Learned addresses are forgotten after failing this many times in a row.
+pub const MAX_FAILURES: i64 = 5;
+
+/// How far away an address is reachable from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Scope {
+    Loopback,
+    LinkLocal,
+    Private,
+    Global,
+}
+
+/// IPv4-mapped IPv6 addresses are treated as the IPv4 addresses they carry.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+pub fn scope(ip: IpAddr) -> Scope {
+    match canonical(ip) {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            if ip.is_loopback() {
+                Scope::Loopback
+            } else if ip.is_link_local() {
+                Scope::LinkLocal
+            } else if ip.is_private() || (octets[0] == 100 && octets[1] & 0xc0 == 64) {
+                // 100.64.0.0/10 is shared address space behind carrier NATs.
+                Scope::Private
+            } else {
+                Scope::Global
+            }
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            if ip.is_loopback() {
+                Scope::Loopback
+            } else if first & 0xffc0 == 0xfe80 {
+                Scope::LinkLocal
+            } else if first & 0xfe00 == 0xfc00 {
+                Scope::Private
+            } else {
+                Scope::Global
+            }
+        }
+    }
+}
+
+/// Returns the canonical form of addresses worth dialing from a node on
+/// `local_scope`. Addresses narrower than global are only reachable from the
+/// same scope.
+fn normalize(address: &str, local_scope: Scope) -> Option<String> {
+    let address = address.parse::<SocketAddr>().ok()?;
+    let ip = canonical(address.ip());
+    let broadcast = match ip {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        IpAddr::V6(_) => false,
+    };
+    if address.port() == 0 || ip.is_unspecified() || ip.is_multicast() || broadcast {
+        return None;
+    }
+    let scope = scope(ip);
+    if scope != Scope::Global && scope != local_scope {
+        return None;
+    }
+    Some(SocketAddr::new(ip, address.port()).to_string())
+}
+
+/// Keeps addresses handed out by `source`, and returns the ones that weren't
+/// known before. Addresses the operator added are left alone.
+pub fn merge(pool: Pool, source: &str, local_scope: Scope, addresses: &[String]) -> Vec<String> {
+    learned_peers::expire(pool.clone(), LEARNED_PEER_LIFETIME, MAX_FAILURES);
+    let mut learned = Vec::new();
+    for address in addresses
+        .iter()
+        .take(SAMPLE_SIZE as usize)
+        .filter_map(|address| normalize(address, local_scope))
+    {
+        if peers::retrieve(pool.clone(), &address).is_some()
+            || learned_peers::touch(pool.clone(), &address)
+        {
+            continue;
+        }
+        if learned.len() >= MAX_LEARNED_PER_SESSION
+            || learned_peers::count_from_source(pool.clone(), source) >= MAX_LEARNED_PER_SOURCE
+            || learned_peers::count(pool.clone()) >= MAX_LEARNED_PEERS
+        {
+            break;
+        }
+        if learned_peers::insert(pool.clone(), &address, source) {
+            learned.push(address);
+        }
+    }
+    learned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    fn pool() -> Pool {
+        let pool = std::sync::Arc::new(
+            r2d2::Pool::builder()
+                .max_size(1)
+                .build(SqliteConnectionManager::memory())
+                .unwrap(),
+        );
+        crate::migrations::migrate(pool.clone()).unwrap();
+        pool
+    }
+
+    fn addresses(first: u8, count: u8) -> Vec<String> {
+        (first..first + count)
+            .map(|i| format!("203.0.113.{}:1234", i))
+            .collect()
+    }
+
+    #[test]
+    fn narrow_scopes_are_only_accepted_on_the_same_scope() {
+        for (address, scope) in &[
+            ("127.0.0.1:1", Scope::Loopback),
+            ("[::1]:1", Scope::Loopback),
+            ("[::ffff:127.0.0.1]:1", Scope::Loopback),
+            ("169.254.1.1:1", Scope::LinkLocal),
+            ("[fe80::1]:1", Scope::LinkLocal),
+            ("10.0.0.1:1", Scope::Private),
+            ("192.168.1.1:1", Scope::Private),
+            ("100.64.0.1:1", Scope::Private),
+            ("[fd00::1]:1", Scope::Private),
+        ] {
+            assert_eq!(normalize(address, Scope::Global), None);
+            assert!(normalize(address, *scope).is_some());
+        }
+        assert_eq!(
+            normalize("[::ffff:203.0.113.1]:1", Scope::Global),
+            Some("203.0.113.1:1".to_owned())
+        );
+        for address in &[
+            "0.0.0.0:1",
+            "203.0.113.1:0",
+            "224.0.0.1:1",
+            "255.255.255.255:1",
+        ] {
+            assert_eq!(normalize(address, Scope::Loopback), None);
+        }
+    }
+
+    #[test]
+    fn learned_peers_stay_out_of_the_address_book() {
+        let pool = pool();
+        let learned = merge(pool.clone(), "source", Scope::Global, &addresses(1, 4));
+        assert_eq!(learned.len(), 4);
+        assert!(peers::list(pool.clone()).is_empty());
+        assert_eq!(learned_peers::list(pool.clone()).len(), 4);
+    }
+
+    #[test]
+    fn operator_peers_are_not_learned() {
+        let pool = pool();
+        peers::insert(pool.clone(), "203.0.113.1:1234", false);
+        assert!(merge(pool.clone(), "source", Scope::Global, &addresses(1, 1)).is_empty());
+        assert!(learned_peers::list(pool).is_empty());
+    }
+
+    #[test]
+    fn sources_are_capped() {
+        let pool = pool();
+        let mut total = 0;
+        for session in 0..8 {
+            let offered = addresses(session * SAMPLE_SIZE as u8, SAMPLE_SIZE as u8);
+            total += merge(pool.clone(), "greedy", Scope::Global, &offered).len();
+        }
+        assert_eq!(total as i64, MAX_LEARNED_PER_SOURCE);
+        let learned = merge(pool.clone(), "other", Scope::Global, &addresses(200, 4));
+        assert_eq!(learned.len(), 4);
+    }
+
+    #[test]
+    fn stale_and_unreachable_peers_expire() {
+        let pool = pool();
+        merge(pool.clone(), "source", Scope::Global, &addresses(1, 2));
+        for _ in 0..MAX_FAILURES {
+            learned_peers::record_failure(pool.clone(), "203.0.113.1:1234");
+        }
+        learned_peers::expire(pool.clone(), LEARNED_PEER_LIFETIME, MAX_FAILURES);
+        assert!(learned_peers::retrieve(pool.clone(), "203.0.113.1:1234").is_none());
+        assert!(learned_peers::retrieve(pool.clone(), "203.0.113.2:1234").is_some());
+        learned_peers::expire(pool.clone(), -1, MAX_FAILURES);
+        assert!(learned_peers::list(pool).is_empty());
+    }
+
+    #[test]
+    fn reached_peers_are_handed_out() {
+        let pool = pool();
+        peers::insert(pool.clone(), "203.0.113.1:1234", true);
+        peers::insert(pool.clone(), "203.0.113.2:1234", false);
+        merge(pool.clone(), "source", Scope::Global, &addresses(3, 2));
+        assert!(peers::sample(pool.clone(), SAMPLE_SIZE).is_empty());
+        for i in 1..=4 {
+            let address = format!("203.0.113.{}:1234", i);
+            peers::record_success(pool.clone(), &address);
+            learned_peers::record_success(pool.clone(), &address);
+        }
+        let mut sample = peers::sample(pool, SAMPLE_SIZE);
+        sample.sort();
+        assert_eq!(
+            sample,
+            ["203.0.113.1:1234", "203.0.113.3:1234", "203.0.113.4:1234"]
+        );
+    }
+}
@@ -55,15 +55,25 @@ pub fn get_expected_target2(payload: &[u8], expiration_time: i64) -> Option<u64>
     Some(expected_target)
 }
 
-pub fn verify(payload: &[u8], nonce: i64, expiration_time: i64) -> bool {
-    let expected_target = match get_expected_target2(payload, expiration_time) {
-        Some(target) => target,
-        None => return false,
+/// Private networks can key the hash with their network key, so the proof of
+/// work is only valid within the network.
+fn payload_hash(payload: &[u8], key: Option<&[u8]>) -> [u8; 64] {
+    let mut hasher = match key {
+        Some(key) => Blake2b::new_keyed(64, key),
+        None => Blake2b::new(64),
     };
-    let mut hasher = Blake2b::new(64);
     hasher.input(payload);
     let mut payload_hash = [0u8; 64];
     hasher.result(&mut payload_hash);
+    payload_hash
+}
+
+pub fn verify(payload: &[u8], nonce: i64, expiration_time: i64, key: Option<&[u8]>) -> bool {
+    let expected_target = match get_expected_target2(payload, expiration_time) {
+        Some(target) => target,
+        None => return false,
+    };
+    let payload_hash = payload_hash(payload, key);
     let current_target = get_current_target(&payload_hash, nonce);
     current_target <= expected_target
 }
@@ -74,17 +84,14 @@ pub async fn prove(
     payload: &[u8],
     target: u64,
     cancelled: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    key: Option<&[u8]>,
 ) -> Option<i64> {
     let channel = std::sync::Arc::new(UnbufferedChannel::<Option<i64>>::new());
     let threads = num_cpus::get();
-    let mut hasher = Blake2b::new(64);
-    hasher.input(payload);
-    let mut payload_hash = [0u8; 64];
-    hasher.result(&mut payload_hash);
+    let payload_hash = payload_hash(payload, key);
     for _ in 0..threads {
         let channel = channel.clone();
         let cancelled = cancelled.clone();
-        let payload_hash = payload_hash;
         std::thread::spawn(move || {
             let mut rng = rand::thread_rng();
             loop {
@@ -114,3 +121,14 @@ pub async fn prove(
     }
     unreachable!()
 }
+
+/// How many times easier the target is than the proof of work. Messages
+/// that took more work than required rank higher when the inventory is full.
+pub fn surplus(payload: &[u8], nonce: i64, expiration_time: i64, key: Option<&[u8]>) -> f64 {
+    let expected_target = match get_expected_target2(payload, expiration_time) {
+        Some(target) => target,
+        None => return 0.0,
+    };
+    let current_target = get_current_target(&payload_hash(payload, key), nonce);
+    expected_target as f64 / current_target.max(1) as f64
+}
@@ -0,0 +1,192 @@
+use crate::die_on_error::die_on_error;
+use crate::identity::{self, ActiveSessions, Identity, NONCE_LENGTH};
+use crate::inventory::Inventory;
+use crate::pex;
+use crate::rate_limit::Limits;
+use crate::reconcile_capnp::hello;
+use crate::signed_envelope::SigningKey;
+use futures::channel::mpsc;
+use futures_intrusive::sync::LocalManualResetEvent;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::rc::Rc;
+
+/// Bumped whenever the schema or the proof of work parameters change in an
+/// incompatible way.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const DEFAULT_NETWORK_ID: &str = "contrasleuth";
+
+pub const FEATURE_RANGES: &str = "ranges";
+pub const FEATURE_SKETCH: &str = "sketch";
+pub const FEATURE_QUERY_MANY: &str = "queryMany";
+pub const FEATURE_SUBMIT_MANY: &str = "submitMany";
+pub const FEATURE_SUBSCRIBE: &str = "subscribe";
+pub const FEATURE_HASHES_SINCE: &str = "hashesSince";
+pub const FEATURE_PEERS: &str = "peers";
+/// Peers advertising this must prove they hold the key in their hello.
+pub const FEATURE_IDENTITY: &str = "identity";
+
+/// Optional methods served by this node.
+pub const FEATURES: &[&str] = &[
+    FEATURE_RANGES,
+    FEATURE_SKETCH,
+    FEATURE_QUERY_MANY,
+    FEATURE_SUBMIT_MANY,
+    FEATURE_SUBSCRIBE,
+    FEATURE_HASHES_SINCE,
+    FEATURE_PEERS,
+    FEATURE_IDENTITY,
+];
+
+/// Settings and state shared by every reconciliation session.
+pub struct SessionOptions {
+    pub batch_size: usize,
+    pub network_id: String,
+    pub limits: Limits,
+    /// Skips the Noise handshake. Only meant for testing.
+    pub plaintext: bool,
+    /// SOCKS5 proxy that outbound connections go through, as `host:port`.
+    pub proxy: Option<String>,
+    /// Scope of the listen address. Learned addresses on narrower scopes are
+    /// dropped.
+    pub local_scope: pex::Scope,
+    /// Addresses learned through peer exchange, for the peer manager to dial.
+    pub learned_peers: mpsc::UnboundedSender<String>,
+    /// Pre-shared key of a private network, mixed into the Noise handshake.
+    pub network_key: Option<[u8; 32]>,
+    /// Set to the network key when messages shouldn't cross into other
+    /// networks. Keys the proof of work.
+    pub proof_of_work_key: Option<[u8; 32]>,
+    pub inventory: Inventory,
+    pub identity: Identity,
+    /// Signs envelopes submitted over IPC.
+    pub signing_key: SigningKey,
+    pub active_sessions: RefCell<ActiveSessions>,
+}
+
+#[derive(Debug)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub network_id: String,
+    pub features: HashSet<String>,
+    pub software_version: String,
+    /// Empty for peers that don't advertise identity support.
+    pub public_key: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Hello {
+    pub fn ours(options: &SessionOptions, session: &Session) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            network_id: options.network_id.clone(),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            software_version: SOFTWARE_VERSION.to_owned(),
+            public_key: options.identity.public_key.to_vec(),
+            nonce: session.nonce.to_vec(),
+            signature: Vec::new(),
+        }
+    }
+
+    /// Answers a hello request, proving we hold our key.
+    pub fn reply(options: &SessionOptions, session: &Session, request: &Hello) -> Hello {
+        let mut hello = Hello::ours(options, session);
+        hello.signature = options
+            .identity
+            .sign(&identity::challenge(
+                &request.nonce,
+                &session.nonce,
+                &session.handshake_hash,
+            ))
+            .to_vec();
+        hello
+    }
+
+    /// Whether the peer has to prove it holds a key, which it does if it
+    /// advertises identity support or presents a key at all.
+    pub fn claims_identity(&self) -> bool {
+        self.features.contains(FEATURE_IDENTITY) || !self.public_key.is_empty()
+    }
+
+    /// Checks the signature on a reply to our request.
+    pub fn is_authentic(&self, session: &Session) -> bool {
+        identity::verify(
+            &self.public_key,
+            &identity::challenge(&session.nonce, &self.nonce, &session.handshake_hash),
+            &self.signature,
+        )
+    }
+}
+
+pub fn read_hello(reader: hello::Reader) -> Result<Hello, capnp::Error> {
+    let mut features = HashSet::new();
+    for feature in reader.get_features()?.iter() {
+        features.insert(feature?.to_owned());
+    }
+    Ok(Hello {
+        protocol_version: reader.get_protocol_version(),
+        network_id: reader.get_network_id()?.to_owned(),
+        features,
+        software_version: reader.get_software_version()?.to_owned(),
+        public_key: reader.get_public_key()?.to_vec(),
+        nonce: reader.get_nonce()?.to_vec(),
+        signature: reader.get_signature()?.to_vec(),
+    })
+}
+
+pub fn set_hello(mut builder: hello::Builder, hello: &Hello) {
+    builder.set_protocol_version(hello.protocol_version);
+    builder.set_network_id(&hello.network_id);
+    builder.set_software_version(&hello.software_version);
+    builder.set_public_key(&hello.public_key);
+    builder.set_nonce(&hello.nonce);
+    builder.set_signature(&hello.signature);
+    let mut features = builder.init_features(die_on_error(hello.features.len().try_into()));
+    for (i, feature) in hello.features.iter().enumerate() {
+        features.set(die_on_error(i.try_into()), feature);
+    }
+}
+
+/// What is known about the peer on the other end of a session.
+pub struct Session {
+    /// The peer's reply to our hello.
+    pub peer: Option<Hello>,
+    /// Set once the peer's reply to our hello passed every check. Only then
+    /// are its calls served.
+    pub authenticated: bool,
+    /// Set instead if the peer predates the handshake. Such peers are only
+    /// served the calls they know.
+    pub legacy: bool,
+    /// Set when the handshake is over. Calls that arrive sooner wait for it.
+    pub settled: Rc<LocalManualResetEvent>,
+    /// Sent in our hello so the peer can prove it holds its key.
+    pub nonce: [u8; NONCE_LENGTH],
+    /// Empty for plaintext connections.
+    pub handshake_hash: Vec<u8>,
+}
+
+impl Session {
+    pub fn new(handshake_hash: Vec<u8>) -> Session {
+        Session {
+            peer: None,
+            authenticated: false,
+            legacy: false,
+            settled: Rc::new(LocalManualResetEvent::new(false)),
+            nonce: identity::generate_nonce(),
+            handshake_hash,
+        }
+    }
+
+    /// Nothing is supported until the peer has said hello.
+    pub fn supports(&self, feature: &str) -> bool {
+        match &self.peer {
+            Some(hello) => hello.features.contains(feature),
+            None => false,
+        }
+    }
+}
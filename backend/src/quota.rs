@@ -0,0 +1,46 @@
+/// Decides which message goes when the inventory is over quota. Incoming
+/// messages only push out messages the policy ranks below them, and are
+/// rejected otherwise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    SoonestExpiring,
+    Largest,
+    LowestSurplus,
+}
+
+impl EvictionPolicy {
+    pub fn parse(value: &str) -> Option<EvictionPolicy> {
+        match value {
+            "soonest-expiring" => Some(EvictionPolicy::SoonestExpiring),
+            "largest" => Some(EvictionPolicy::Largest),
+            "lowest-surplus" => Some(EvictionPolicy::LowestSurplus),
+            _ => None,
+        }
+    }
+}
+
+/// Limits on the inventory. Sizes count payload bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_messages: Option<u64>,
+    pub eviction_policy: EvictionPolicy,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_are_parsed_by_name() {
+        for (name, policy) in &[
+            ("soonest-expiring", EvictionPolicy::SoonestExpiring),
+            ("largest", EvictionPolicy::Largest),
+            ("lowest-surplus", EvictionPolicy::LowestSurplus),
+        ] {
+            assert_eq!(EvictionPolicy::parse(name), Some(*policy));
+        }
+        assert_eq!(EvictionPolicy::parse("Largest"), None);
+        assert_eq!(EvictionPolicy::parse(""), None);
+    }
+}
@@ -0,0 +1,199 @@
+use crypto::blake2b::Blake2b;
+use crypto::digest::Digest;
+
+/// Ranges holding at most this many hashes on either side are settled by
+/// exchanging the hashes themselves instead of recursing further.
+pub const LEAF_SIZE: usize = 32;
+
+/// Number of subranges a mismatching range is split into.
+pub const BRANCHING_FACTOR: usize = 16;
+
+/// Number of ranges a single call may ask about. Longer lists are sent in
+/// several calls.
+pub const MAX_RANGES: usize = BRANCHING_FACTOR * 64;
+
+/// A half-open interval of the hash space. An empty upper bound denotes the
+/// end of the hash space.
+#[derive(Clone, Debug)]
+pub struct Range {
+    pub lower_bound: Vec<u8>,
+    pub upper_bound: Vec<u8>,
+}
+
+impl Range {
+    pub fn full() -> Range {
+        Range {
+            lower_bound: Vec::new(),
+            upper_bound: Vec::new(),
+        }
+    }
+
+    /// Returns an upper bound suitable for comparison against stored hashes.
+    /// Hashes are 64 bytes long, so 65 bytes of 0xff sort after all of them.
+    pub fn effective_upper_bound(&self) -> Vec<u8> {
+        if self.upper_bound.is_empty() {
+            vec![0xff; 65]
+        } else {
+            self.upper_bound.clone()
+        }
+    }
+}
+
+/// Hashes are expected to be sorted in ascending order.
+pub fn fingerprint(hashes: &[Vec<u8>]) -> Vec<u8> {
+    let mut hasher = Blake2b::new(32);
+    for hash in hashes {
+        hasher.input(hash);
+    }
+    let mut result = [0u8; 32];
+    hasher.result(&mut result);
+    result.to_vec()
+}
+
+/// Splits a range into subranges holding roughly the same number of the
+/// given (sorted) hashes.
+pub fn split(range: &Range, hashes: &[Vec<u8>]) -> Vec<Range> {
+    let mut ranges = Vec::new();
+    let mut lower_bound = range.lower_bound.clone();
+    for i in 1..BRANCHING_FACTOR {
+        let boundary = &hashes[i * hashes.len() / BRANCHING_FACTOR];
+        if *boundary <= lower_bound {
+            continue;
+        }
+        ranges.push(Range {
+            lower_bound,
+            upper_bound: boundary.clone(),
+        });
+        lower_bound = boundary.clone();
+    }
+    ranges.push(Range {
+        lower_bound,
+        upper_bound: range.upper_bound.clone(),
+    });
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(seed: u32) -> Vec<u8> {
+        let mut hasher = Blake2b::new(64);
+        hasher.input(&seed.to_be_bytes());
+        let mut result = [0u8; 64];
+        hasher.result(&mut result);
+        result.to_vec()
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn sorted_hashes(count: u32) -> Vec<Vec<u8>> {
+        let mut hashes: Vec<_> = (0..count).map(hash).collect();
+        hashes.sort();
+        hashes
+    }
+
+    fn contains(range: &Range, hash: &[u8]) -> bool {
+        *hash >= *range.lower_bound && *hash < *range.effective_upper_bound()
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        // Peers running different builds must agree on fingerprints, so the
+        // construction is pinned to BLAKE2b-256 over the concatenated hashes.
+        assert_eq!(
+            to_hex(&fingerprint(&[])),
+            "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8"
+        );
+        let mut hasher = Blake2b::new(64);
+        hasher.input(&[1]);
+        let mut first = [0u8; 64];
+        hasher.result(&mut first);
+        let mut hasher = Blake2b::new(64);
+        hasher.input(&[2]);
+        let mut second = [0u8; 64];
+        hasher.result(&mut second);
+        assert_eq!(
+            to_hex(&fingerprint(&[first.to_vec(), second.to_vec()])),
+            "071c9719c6ee6713d06f6be2e821d536eb5419229c4273acf07f1cdcc1567888"
+        );
+    }
+
+    #[test]
+    fn fingerprint_depends_on_every_hash() {
+        let hashes = sorted_hashes(100);
+        assert_eq!(fingerprint(&hashes), fingerprint(&hashes.clone()));
+        assert_ne!(fingerprint(&hashes), fingerprint(&hashes[1..]));
+        let mut altered = hashes.clone();
+        altered[50][0] ^= 1;
+        assert_ne!(fingerprint(&hashes), fingerprint(&altered));
+    }
+
+    #[test]
+    fn split_covers_the_range_without_overlap() {
+        let hashes = sorted_hashes(1000);
+        let ranges = split(&Range::full(), &hashes);
+        assert_eq!(ranges.len(), BRANCHING_FACTOR);
+        assert!(ranges[0].lower_bound.is_empty());
+        assert!(ranges[ranges.len() - 1].upper_bound.is_empty());
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].upper_bound, pair[1].lower_bound);
+        }
+        for hash in &hashes {
+            assert_eq!(
+                ranges.iter().filter(|range| contains(range, hash)).count(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn split_balances_hashes() {
+        let hashes = sorted_hashes(1600);
+        for range in split(&Range::full(), &hashes) {
+            let count = hashes.iter().filter(|hash| contains(&range, hash)).count();
+            assert_eq!(count, 100);
+        }
+    }
+
+    #[test]
+    fn split_keeps_the_bounds_of_a_subrange() {
+        let hashes = sorted_hashes(1000);
+        let range = Range {
+            lower_bound: hashes[100].clone(),
+            upper_bound: hashes[900].clone(),
+        };
+        let inside: Vec<_> = hashes[100..900].to_vec();
+        let ranges = split(&range, &inside);
+        assert_eq!(ranges[0].lower_bound, range.lower_bound);
+        assert_eq!(ranges[ranges.len() - 1].upper_bound, range.upper_bound);
+        for pair in ranges.windows(2) {
+            assert!(pair[0].lower_bound < pair[0].upper_bound);
+            assert_eq!(pair[0].upper_bound, pair[1].lower_bound);
+        }
+    }
+
+    #[test]
+    fn split_skips_empty_subranges() {
+        // With fewer hashes than branches, boundaries repeat; no subrange may
+        // end where it starts.
+        let hashes = sorted_hashes(LEAF_SIZE as u32 / 4);
+        let range = Range {
+            lower_bound: hashes[0].clone(),
+            upper_bound: Vec::new(),
+        };
+        let ranges = split(&range, &hashes);
+        assert!(ranges.len() < BRANCHING_FACTOR);
+        for range in &ranges[..ranges.len() - 1] {
+            assert!(range.lower_bound < range.upper_bound);
+        }
+        for hash in &hashes {
+            assert_eq!(
+                ranges.iter().filter(|range| contains(range, hash)).count(),
+                1
+            );
+        }
+    }
+}
@@ -0,0 +1,612 @@
+use crate::die_on_error::die_on_error;
+use crate::envelope;
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use rand::RngCore;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
+
+// Message layout: magic, version, kind, the recipient key the session was
+// started against (prekey messages only), the sender's ratchet public key,
+// the length of the sender's previous sending chain, the message number,
+// then the encrypted payload. The header is authenticated with the payload.
+const MAGIC: &[u8] = b"CSDR";
+const VERSION: u8 = 1;
+const MESSAGE: u8 = 0;
+const PREKEY_MESSAGE: u8 = 1;
+const KEY_LENGTH: usize = 32;
+const TAG_LENGTH: usize = 16;
+const SESSION_ID_LENGTH: usize = 16;
+
+/// Bounds the work and storage a single message can cause.
+const MAX_SKIP: u32 = 1000;
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// Every message key encrypts exactly one message, so the nonce can be fixed.
+const NONCE: [u8; 8] = [0; 8];
+
+/// The first root key. The secret comes from the Diffie-Hellman output mixed
+/// into it.
+const INITIAL_ROOT_KEY: &[u8] = b"contrasleuth double ratchet";
+
+struct Header {
+    prekey: Option<Vec<u8>>,
+    ratchet_key: Vec<u8>,
+    previous_chain_length: u32,
+    number: u32,
+    length: usize,
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buffer = [0u8; 4];
+    buffer.copy_from_slice(bytes);
+    u32::from_be_bytes(buffer)
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        match &self.prekey {
+            Some(prekey) => {
+                header.push(PREKEY_MESSAGE);
+                header.extend_from_slice(prekey);
+            }
+            None => header.push(MESSAGE),
+        }
+        header.extend_from_slice(&self.ratchet_key);
+        header.extend_from_slice(&self.previous_chain_length.to_be_bytes());
+        header.extend_from_slice(&self.number.to_be_bytes());
+        header
+    }
+
+    fn parse(message: &[u8]) -> Option<Header> {
+        let start = MAGIC.len() + 2;
+        if message.len() < start || !message.starts_with(MAGIC) || message[MAGIC.len()] != VERSION {
+            return None;
+        }
+        let (prekey, start) = match message[MAGIC.len() + 1] {
+            MESSAGE => (None, start),
+            PREKEY_MESSAGE => (
+                Some(message.get(start..start + KEY_LENGTH)?.to_vec()),
+                start + KEY_LENGTH,
+            ),
+            _ => return None,
+        };
+        let fields = message.get(start..start + KEY_LENGTH + 8)?;
+        Some(Header {
+            prekey,
+            ratchet_key: fields[..KEY_LENGTH].to_vec(),
+            previous_chain_length: read_u32(&fields[KEY_LENGTH..KEY_LENGTH + 4]),
+            number: read_u32(&fields[KEY_LENGTH + 4..]),
+            length: start + KEY_LENGTH + 8,
+        })
+    }
+}
+
+fn random_key() -> [u8; KEY_LENGTH] {
+    let mut key = [0u8; KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/// Returns the next root key and a chain key.
+fn root_step(root_key: &[u8], dh_output: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut prk = [0u8; 32];
+    hkdf_extract(Sha256::new(), root_key, dh_output, &mut prk);
+    let mut output = [0u8; 2 * KEY_LENGTH];
+    hkdf_expand(Sha256::new(), &prk, b"contrasleuth ratchet", &mut output);
+    (output[..KEY_LENGTH].to_vec(), output[KEY_LENGTH..].to_vec())
+}
+
+/// Returns the next chain key and a message key.
+fn chain_step(chain_key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let derive = |constant: u8| {
+        let mut hmac = Hmac::new(Sha256::new(), chain_key);
+        hmac.input(&[constant]);
+        hmac.result().code().to_vec()
+    };
+    (derive(2), derive(1))
+}
+
+fn encrypt(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut output = vec![0u8; plaintext.len() + TAG_LENGTH];
+    let (ciphertext, tag) = output.split_at_mut(plaintext.len());
+    ChaCha20Poly1305::new(key, &NONCE, aad).encrypt(plaintext, ciphertext, tag);
+    output
+}
+
+fn decrypt(key: &[u8], aad: &[u8], input: &[u8]) -> Option<Vec<u8>> {
+    if input.len() < TAG_LENGTH {
+        return None;
+    }
+    let (ciphertext, tag) = input.split_at(input.len() - TAG_LENGTH);
+    let mut plaintext = vec![0u8; ciphertext.len()];
+    if ChaCha20Poly1305::new(key, &NONCE, aad).decrypt(ciphertext, &mut plaintext, tag) {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SkippedKey {
+    ratchet_key: Vec<u8>,
+    number: u32,
+    message_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct State {
+    root_key: Vec<u8>,
+    private_key: Vec<u8>,
+    public_key: Vec<u8>,
+    their_public_key: Vec<u8>,
+    sending_chain_key: Vec<u8>,
+    receiving_chain_key: Option<Vec<u8>>,
+    sent: u32,
+    received: u32,
+    previous_sent: u32,
+    skipped: Vec<SkippedKey>,
+    /// The recipient key the session was started against. Messages name it
+    /// until the other side replies, so any of them can start its session.
+    prekey: Option<Vec<u8>>,
+}
+
+impl State {
+    fn initiator(recipient: &[u8]) -> State {
+        let private_key = random_key();
+        let (root_key, sending_chain_key) =
+            root_step(INITIAL_ROOT_KEY, &curve25519(&private_key, recipient));
+        State {
+            root_key,
+            private_key: private_key.to_vec(),
+            public_key: curve25519_base(&private_key).to_vec(),
+            their_public_key: recipient.to_vec(),
+            sending_chain_key,
+            receiving_chain_key: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: Vec::new(),
+            prekey: Some(recipient.to_vec()),
+        }
+    }
+
+    /// The recipient key is the responder's first ratchet key. It is replaced
+    /// right away, so it only protects the initiator's first sending chain.
+    fn responder(key: &envelope::RecipientKey, their_public_key: &[u8]) -> State {
+        let mut state = State {
+            root_key: INITIAL_ROOT_KEY.to_vec(),
+            private_key: key.private_key.clone(),
+            public_key: key.public_key.clone(),
+            their_public_key: Vec::new(),
+            sending_chain_key: Vec::new(),
+            receiving_chain_key: None,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: Vec::new(),
+            prekey: None,
+        };
+        state.step(their_public_key);
+        state
+    }
+
+    /// Moves to the other side's new ratchet key.
+    fn step(&mut self, their_public_key: &[u8]) {
+        self.previous_sent = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.their_public_key = their_public_key.to_vec();
+        let (root_key, receiving_chain_key) = root_step(
+            &self.root_key,
+            &curve25519(&self.private_key, their_public_key),
+        );
+        let private_key = random_key();
+        let (root_key, sending_chain_key) =
+            root_step(&root_key, &curve25519(&private_key, their_public_key));
+        self.root_key = root_key;
+        self.receiving_chain_key = Some(receiving_chain_key);
+        self.sending_chain_key = sending_chain_key;
+        self.private_key = private_key.to_vec();
+        self.public_key = curve25519_base(&private_key).to_vec();
+    }
+
+    /// Keeps the keys of messages that haven't arrived yet.
+    fn skip_until(&mut self, until: u32) -> Option<()> {
+        let chain_key = match &self.receiving_chain_key {
+            Some(chain_key) => chain_key.clone(),
+            None => return Some(()),
+        };
+        if until > self.received.saturating_add(MAX_SKIP) {
+            return None;
+        }
+        let mut chain_key = chain_key;
+        while self.received < until {
+            let (next_chain_key, message_key) = chain_step(&chain_key);
+            self.skipped.push(SkippedKey {
+                ratchet_key: self.their_public_key.clone(),
+                number: self.received,
+                message_key,
+            });
+            chain_key = next_chain_key;
+            self.received += 1;
+        }
+        self.receiving_chain_key = Some(chain_key);
+        if self.skipped.len() > MAX_SKIPPED_KEYS {
+            self.skipped.drain(..self.skipped.len() - MAX_SKIPPED_KEYS);
+        }
+        Some(())
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let (chain_key, message_key) = chain_step(&self.sending_chain_key);
+        let mut message = Header {
+            prekey: self.prekey.clone(),
+            ratchet_key: self.public_key.clone(),
+            previous_chain_length: self.previous_sent,
+            number: self.sent,
+            length: 0,
+        }
+        .encode();
+        let body = encrypt(&message_key, &message, plaintext);
+        message.extend(body);
+        self.sending_chain_key = chain_key;
+        self.sent += 1;
+        message
+    }
+
+    /// Returns the updated state along with the plaintext, so failures leave
+    /// the session as it was.
+    fn decrypt(&self, message: &[u8]) -> Option<(State, Vec<u8>)> {
+        let header = Header::parse(message)?;
+        let (aad, body) = message.split_at(header.length);
+        let mut state = self.clone();
+        if let Some(position) = state
+            .skipped
+            .iter()
+            .position(|key| key.ratchet_key == header.ratchet_key && key.number == header.number)
+        {
+            let key = state.skipped.remove(position);
+            let plaintext = decrypt(&key.message_key, aad, body)?;
+            return Some((state, plaintext));
+        }
+        if header.ratchet_key != state.their_public_key {
+            state.skip_until(header.previous_chain_length)?;
+            state.step(&header.ratchet_key);
+        }
+        state.skip_until(header.number)?;
+        let (chain_key, message_key) = chain_step(state.receiving_chain_key.as_ref()?);
+        let plaintext = decrypt(&message_key, aad, body)?;
+        state.receiving_chain_key = Some(chain_key);
+        state.received += 1;
+        state.prekey = None;
+        Some((state, plaintext))
+    }
+}
+
+fn put(pool: &Pool, id: &[u8], state: &State) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/F. Ratchet sessions/1. Put ratchet session.sql"),
+        params![id, die_on_error(serde_json::to_vec(state))],
+    ));
+}
+
+fn retrieve(pool: &Pool, id: &[u8]) -> Option<State> {
+    let connection = die_on_error(pool.get());
+    let mut statement = die_on_error(connection.prepare(include_str!(
+        "../sql/F. Ratchet sessions/2. Retrieve ratchet session.sql"
+    )));
+    let mut rows = die_on_error(statement.query(params![id]));
+    let row = die_on_error(rows.next())?;
+    let state: Vec<u8> = die_on_error(row.get(0));
+    Some(die_on_error(serde_json::from_slice(&state)))
+}
+
+/// The session started by the prekey message with this prekey and ratchet
+/// key, even if it has since been removed.
+fn accepted_session(pool: &Pool, prekey: &[u8], ratchet_key: &[u8]) -> Option<Vec<u8>> {
+    let connection = die_on_error(pool.get());
+    let mut statement = die_on_error(connection.prepare(include_str!(
+        "../sql/F. Ratchet sessions/3. Retrieve accepted prekey.sql"
+    )));
+    let mut rows = die_on_error(statement.query(params![prekey, ratchet_key]));
+    let row = die_on_error(rows.next())?;
+    Some(die_on_error(row.get(0)))
+}
+
+/// Returns whether the prekey and ratchet key weren't accepted before.
+fn record_accepted(pool: &Pool, prekey: &[u8], ratchet_key: &[u8], id: &[u8]) -> bool {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/F. Ratchet sessions/5. Put accepted prekey.sql"),
+        params![prekey, ratchet_key, id],
+    )) > 0
+}
+
+type SessionLock = Arc<async_std::sync::Mutex<()>>;
+
+/// Keeps the operations on each session in order, as each one moves its
+/// state forward.
+#[derive(Clone, Default)]
+pub struct SessionLocks(Arc<Mutex<HashMap<Vec<u8>, SessionLock>>>);
+
+impl SessionLocks {
+    pub fn get(&self, id: &[u8]) -> SessionLock {
+        die_on_error(self.0.lock())
+            .entry(id.to_vec())
+            .or_default()
+            .clone()
+    }
+
+    pub fn forget(&self, id: &[u8]) {
+        die_on_error(self.0.lock()).remove(id);
+    }
+}
+
+/// The session a prekey message belongs to, if it was accepted before.
+pub fn session_of(pool: Pool, message: &[u8]) -> Option<Vec<u8>> {
+    let header = Header::parse(message)?;
+    accepted_session(&pool, &header.prekey?, &header.ratchet_key)
+}
+
+/// Starts a session with the holder of a recipient key. Returns the session
+/// ID, or None if the key is malformed.
+pub fn start(pool: Pool, recipient: &[u8]) -> Option<Vec<u8>> {
+    if recipient.len() != KEY_LENGTH {
+        return None;
+    }
+    let mut id = vec![0u8; SESSION_ID_LENGTH];
+    rand::thread_rng().fill_bytes(&mut id);
+    put(&pool, &id, &State::initiator(recipient));
+    Some(id)
+}
+
+/// Starts a session from a prekey message addressed to one of our recipient
+/// keys and returns its ID with the plaintext. A prekey message of a session
+/// that already exists is decrypted in that session, so a replayed one fails
+/// instead of starting the session again.
+pub fn accept(pool: Pool, message: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let header = Header::parse(message)?;
+    let prekey = header.prekey?;
+    if let Some(id) = accepted_session(&pool, &prekey, &header.ratchet_key) {
+        let (state, plaintext) = retrieve(&pool, &id)?.decrypt(message)?;
+        put(&pool, &id, &state);
+        return Some((id, plaintext));
+    }
+    let key = envelope::list(pool.clone())
+        .into_iter()
+        .find(|key| key.public_key == prekey)?;
+    let (state, plaintext) = State::responder(&key, &header.ratchet_key).decrypt(message)?;
+    let mut id = vec![0u8; SESSION_ID_LENGTH];
+    rand::thread_rng().fill_bytes(&mut id);
+    if !record_accepted(&pool, &prekey, &header.ratchet_key, &id) {
+        return None;
+    }
+    put(&pool, &id, &state);
+    Some((id, plaintext))
+}
+
+/// Returns None if there is no such session.
+pub fn seal(pool: Pool, id: &[u8], plaintext: &[u8]) -> Option<Vec<u8>> {
+    let mut state = retrieve(&pool, id)?;
+    let message = state.encrypt(plaintext);
+    put(&pool, id, &state);
+    Some(message)
+}
+
+/// Returns None if there is no such session or the message doesn't belong
+/// to it.
+pub fn open(pool: Pool, id: &[u8], message: &[u8]) -> Option<Vec<u8>> {
+    let (state, plaintext) = retrieve(&pool, id)?.decrypt(message)?;
+    put(&pool, id, &state);
+    Some(plaintext)
+}
+
+pub fn remove(pool: Pool, id: &[u8]) {
+    die_on_error(die_on_error(pool.get()).execute(
+        include_str!("../sql/F. Ratchet sessions/4. Remove ratchet session.sql"),
+        params![id],
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2_sqlite::SqliteConnectionManager;
+
+    fn recipient_key() -> envelope::RecipientKey {
+        let private_key = random_key();
+        envelope::RecipientKey {
+            public_key: curve25519_base(&private_key).to_vec(),
+            private_key: private_key.to_vec(),
+        }
+    }
+
+    /// Alice starts a session with Bob's recipient key, and Bob accepts her
+    /// first message.
+    fn session() -> (State, State) {
+        let key = recipient_key();
+        let mut alice = State::initiator(&key.public_key);
+        let message = alice.encrypt(b"hello");
+        let header = Header::parse(&message).unwrap();
+        assert_eq!(header.prekey.as_ref(), Some(&key.public_key));
+        let (bob, plaintext) = State::responder(&key, &header.ratchet_key)
+            .decrypt(&message)
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+        (alice, bob)
+    }
+
+    fn receive(state: &mut State, message: &[u8]) -> Option<Vec<u8>> {
+        let (next, plaintext) = state.decrypt(message)?;
+        *state = next;
+        Some(plaintext)
+    }
+
+    fn pool() -> Pool {
+        let pool = std::sync::Arc::new(
+            r2d2::Pool::builder()
+                .max_size(1)
+                .build(SqliteConnectionManager::memory())
+                .unwrap(),
+        );
+        crate::migrations::migrate(pool.clone()).unwrap();
+        pool
+    }
+
+    #[test]
+    fn round_trip_in_order() {
+        let (mut alice, mut bob) = session();
+        for i in 0..5u8 {
+            let message = alice.encrypt(&[i]);
+            assert_eq!(receive(&mut bob, &message).unwrap(), [i]);
+        }
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn out_of_order_delivery_uses_skipped_keys() {
+        let (mut alice, mut bob) = session();
+        let messages: Vec<_> = (0..4u8).map(|i| alice.encrypt(&[i])).collect();
+        assert_eq!(receive(&mut bob, &messages[3]).unwrap(), [3]);
+        assert_eq!(bob.skipped.len(), 3);
+        assert_eq!(receive(&mut bob, &messages[1]).unwrap(), [1]);
+        assert_eq!(receive(&mut bob, &messages[0]).unwrap(), [0]);
+        assert_eq!(receive(&mut bob, &messages[2]).unwrap(), [2]);
+        assert!(bob.skipped.is_empty());
+        // Skipped keys are used up.
+        assert!(receive(&mut bob, &messages[1]).is_none());
+    }
+
+    #[test]
+    fn skipped_keys_from_a_previous_chain_survive_a_ratchet_step() {
+        let (mut alice, mut bob) = session();
+        let late = alice.encrypt(b"late");
+        let on_time = alice.encrypt(b"on time");
+        assert_eq!(receive(&mut bob, &on_time).unwrap(), b"on time");
+        let reply = bob.encrypt(b"reply");
+        assert_eq!(receive(&mut alice, &reply).unwrap(), b"reply");
+        let next = alice.encrypt(b"next");
+        assert_eq!(receive(&mut bob, &next).unwrap(), b"next");
+        assert_eq!(receive(&mut bob, &late).unwrap(), b"late");
+    }
+
+    #[test]
+    fn skipping_too_far_ahead_fails() {
+        let (mut alice, mut bob) = session();
+        let messages: Vec<_> = (0..MAX_SKIP + 2).map(|_| alice.encrypt(b"")).collect();
+        let before = bob.received;
+        assert!(bob.decrypt(messages.last().unwrap()).is_none());
+        assert_eq!(bob.received, before);
+        assert!(bob.skipped.is_empty());
+        // Skipping exactly MAX_SKIP keys is within bounds.
+        assert!(receive(&mut bob, &messages[MAX_SKIP as usize]).is_some());
+        assert_eq!(bob.skipped.len(), MAX_SKIP as usize);
+    }
+
+    #[test]
+    fn skipped_keys_are_capped() {
+        let (mut alice, mut bob) = session();
+        for _ in 0..3 {
+            let messages: Vec<_> = (0..MAX_SKIP).map(|_| alice.encrypt(b"")).collect();
+            receive(&mut bob, messages.last().unwrap()).unwrap();
+            let reply = bob.encrypt(b"");
+            receive(&mut alice, &reply).unwrap();
+        }
+        assert_eq!(bob.skipped.len(), MAX_SKIPPED_KEYS);
+    }
+
+    #[test]
+    fn ratchet_steps_in_both_directions() {
+        let (mut alice, mut bob) = session();
+        let mut alice_keys = vec![alice.public_key.clone()];
+        let mut bob_keys = vec![bob.public_key.clone()];
+        for _ in 0..3 {
+            let message = bob.encrypt(b"from bob");
+            assert_eq!(receive(&mut alice, &message).unwrap(), b"from bob");
+            let message = alice.encrypt(b"from alice");
+            assert_eq!(receive(&mut bob, &message).unwrap(), b"from alice");
+            alice_keys.push(alice.public_key.clone());
+            bob_keys.push(bob.public_key.clone());
+        }
+        alice_keys.dedup();
+        bob_keys.dedup();
+        assert_eq!(alice_keys.len(), 4);
+        assert_eq!(bob_keys.len(), 4);
+        // Once Bob has replied, Alice stops naming his recipient key.
+        assert!(alice.prekey.is_none());
+        assert!(Header::parse(&alice.encrypt(b"")).unwrap().prekey.is_none());
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected() {
+        let (mut alice, mut bob) = session();
+        let message = alice.encrypt(b"payload");
+        let header_length = Header::parse(&message).unwrap().length;
+        for position in &[header_length - 1, header_length, message.len() - 1] {
+            let mut tampered = message.clone();
+            tampered[*position] ^= 1;
+            assert!(bob.decrypt(&tampered).is_none());
+        }
+        assert!(bob.decrypt(&message[..message.len() - 1]).is_none());
+        assert_eq!(receive(&mut bob, &message).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn state_survives_serialization() {
+        let (mut alice, bob) = session();
+        let pending = alice.encrypt(b"pending");
+        let mut bob: State = serde_json::from_slice(&serde_json::to_vec(&bob).unwrap()).unwrap();
+        let mut alice: State =
+            serde_json::from_slice(&serde_json::to_vec(&alice).unwrap()).unwrap();
+        assert_eq!(receive(&mut bob, &alice.encrypt(b"next")).unwrap(), b"next");
+        assert_eq!(receive(&mut bob, &pending).unwrap(), b"pending");
+        assert_eq!(
+            receive(&mut alice, &bob.encrypt(b"reply")).unwrap(),
+            b"reply"
+        );
+    }
+
+    #[test]
+    fn sessions_are_stored_between_calls() {
+        let pool = pool();
+        let recipient = envelope::generate(pool.clone());
+        let alice = start(pool.clone(), &recipient).unwrap();
+        let first = seal(pool.clone(), &alice, b"first").unwrap();
+        let second = seal(pool.clone(), &alice, b"second").unwrap();
+        let (bob, plaintext) = accept(pool.clone(), &first).unwrap();
+        assert_eq!(plaintext, b"first");
+        // Later prekey messages land in the session that already exists.
+        assert_eq!(
+            accept(pool.clone(), &second).unwrap(),
+            (bob.clone(), b"second".to_vec())
+        );
+        let reply = seal(pool.clone(), &bob, b"reply").unwrap();
+        assert_eq!(open(pool.clone(), &alice, &reply).unwrap(), b"reply");
+        remove(pool.clone(), &alice);
+        assert!(seal(pool.clone(), &alice, b"gone").is_none());
+        assert!(start(pool, &recipient[1..]).is_none());
+    }
+
+    #[test]
+    fn replayed_prekey_messages_are_rejected() {
+        let pool = pool();
+        let recipient = envelope::generate(pool.clone());
+        let alice = start(pool.clone(), &recipient).unwrap();
+        let first = seal(pool.clone(), &alice, b"first").unwrap();
+        let (bob, _) = accept(pool.clone(), &first).unwrap();
+        assert!(accept(pool.clone(), &first).is_none());
+        remove(pool.clone(), &bob);
+        assert!(accept(pool, &first).is_none());
+    }
+}
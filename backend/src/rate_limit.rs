@@ -0,0 +1,182 @@
+use crate::log;
+use futures::future::AbortHandle;
+use futures::io::AsyncRead;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Limits applied to every connection.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub requests_per_second: u32,
+    pub bytes_per_second: u64,
+    pub max_payload_size: usize,
+    pub max_concurrent_calls: usize,
+}
+
+/// Allows bursts of up to one second's worth of tokens.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes tokens even if there aren't enough of them, and returns how
+    /// long to wait until the debt is paid off.
+    fn take(&mut self, amount: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Tracks the calls a peer makes on our `Reconcile` capability.
+pub struct RateLimiter {
+    limits: Limits,
+    peer: String,
+    requests: TokenBucket,
+    in_flight: usize,
+    throttled: bool,
+    disconnect: AbortHandle,
+}
+
+pub enum Admission {
+    Proceed,
+    Throttle(Duration),
+    Disconnect,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits, peer: String, disconnect: AbortHandle) -> RateLimiter {
+        RateLimiter {
+            limits,
+            peer,
+            requests: TokenBucket::new(f64::from(limits.requests_per_second)),
+            in_flight: 0,
+            throttled: false,
+            disconnect,
+        }
+    }
+
+    /// Oversized payloads are rejected one by one rather than dropping the
+    /// connection, since peers on older versions push them unchecked.
+    pub fn check_payload_size(&self, size: usize) -> bool {
+        if size <= self.limits.max_payload_size {
+            return true;
+        }
+        log::notice(format!(
+            "Rejecting a payload of {} bytes from {}, the limit is {}",
+            size, self.peer, self.limits.max_payload_size
+        ));
+        false
+    }
+
+    /// Counts the call as in flight unless the peer is disconnected. Calls
+    /// that arrive while throttled wait their turn, so a peer that keeps
+    /// going piles them up until it hits the concurrency cap.
+    pub fn admit(&mut self) -> Admission {
+        if self.in_flight >= self.limits.max_concurrent_calls {
+            log::warning(format!(
+                "Disconnecting {} for exceeding {} concurrent calls",
+                self.peer, self.limits.max_concurrent_calls
+            ));
+            self.disconnect();
+            return Admission::Disconnect;
+        }
+        self.in_flight += 1;
+        let delay = self.requests.take(1.0);
+        if delay == Duration::from_secs(0) {
+            self.throttled = false;
+            return Admission::Proceed;
+        }
+        if !self.throttled {
+            log::notice(format!(
+                "Throttling {} for exceeding {} requests per second",
+                self.peer, self.limits.requests_per_second
+            ));
+            self.throttled = true;
+        }
+        Admission::Throttle(delay)
+    }
+
+    pub fn finish(&mut self) {
+        self.in_flight -= 1;
+    }
+
+    pub fn disconnect(&self) {
+        self.disconnect.abort();
+    }
+}
+
+/// Delays reads once the peer sends more than its share of bytes.
+pub struct ThrottledReader<R> {
+    inner: R,
+    peer: String,
+    bytes_per_second: u64,
+    bytes: TokenBucket,
+    throttled: bool,
+    delay: Option<Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+impl<R> ThrottledReader<R> {
+    pub fn new(inner: R, peer: String, bytes_per_second: u64) -> ThrottledReader<R> {
+        ThrottledReader {
+            inner,
+            peer,
+            bytes_per_second,
+            bytes: TokenBucket::new(bytes_per_second as f64),
+            throttled: false,
+            delay: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context,
+        buffer: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(context).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        let read = match Pin::new(&mut self.inner).poll_read(context, buffer) {
+            Poll::Ready(Ok(read)) => read,
+            other => return other,
+        };
+        let delay = self.bytes.take(read as f64);
+        if delay == Duration::from_secs(0) {
+            self.throttled = false;
+        } else {
+            if !self.throttled {
+                log::notice(format!(
+                    "Throttling {} for exceeding {} bytes per second",
+                    self.peer, self.bytes_per_second
+                ));
+                self.throttled = true;
+            }
+            self.delay = Some(Box::pin(async_std::task::sleep(delay)));
+        }
+        Poll::Ready(Ok(read))
+    }
+}
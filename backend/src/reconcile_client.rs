@@ -1,58 +1,576 @@
+use crate::announcer::Announcer;
 use crate::die_on_error::die_on_error;
-use crate::inventory;
+use crate::iblt::{self, Cell, InvertibleBloomLookupTable};
+use crate::identity;
+use crate::inventory::{self, Insertion, Inventory, PeerCursor};
+use crate::log;
+use crate::message_hash::message_hash;
 use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::noise;
+use crate::pex;
+use crate::protocol::{
+    read_hello, set_hello, Hello, Session, SessionOptions, DEFAULT_NETWORK_ID,
+    FEATURE_HASHES_SINCE, FEATURE_PEERS, FEATURE_QUERY_MANY, FEATURE_RANGES, FEATURE_SKETCH,
+    FEATURE_SUBMIT_MANY, FEATURE_SUBSCRIBE, PROTOCOL_VERSION,
+};
+use crate::range_reconcile::{self, Range};
+use crate::rate_limit::{RateLimiter, ThrottledReader};
+use crate::reconcile_capnp::inventory_listener as InventoryListener;
 use crate::reconcile_capnp::reconcile as Reconcile;
+use crate::reconcile_capnp::{hash_range, maybe_message, message, submit_outcome};
+use crate::reconcile_server;
+use crate::stdio_ipc::{format_struct, Message};
 use async_std::sync::RwLock;
 use async_std::task;
-use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use capnp::capability::Promise;
+use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
+use futures::future::{AbortHandle, Abortable, Aborted};
+use futures::io::{AsyncRead, AsyncWrite};
 use futures::task::LocalSpawn;
 use futures::AsyncReadExt;
 use futures_intrusive::channel::LocalUnbufferedChannel;
 use r2d2_sqlite::SqliteConnectionManager;
+use std::cell::RefCell;
 use std::collections::HashSet;
+use std::convert::TryInto;
 
+type Pool = std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>;
+
+/// Seconds between full comparisons with a node we keep a cursor for, in
+/// case following the insertion sequences missed something.
+const FULL_RECONCILIATION_INTERVAL: i64 = 60 * 60;
+
+#[derive(Debug)]
+enum TerminateOrProceed {
+    Terminate(Result<(), capnp::Error>),
+    Proceed,
+    Announced(Vec<Vec<u8>>),
+}
+
+struct InventoryListenerServer {
+    channel: std::rc::Rc<LocalUnbufferedChannel<TerminateOrProceed>>,
+}
+
+impl InventoryListener::Server for InventoryListenerServer {
+    fn announce(
+        &mut self,
+        params: InventoryListener::AnnounceParams,
+        _results: InventoryListener::AnnounceResults,
+    ) -> Promise<(), capnp::Error> {
+        let channel = self.channel.clone();
+        let mut hashes = Vec::new();
+        for hash in pry!(pry!(params.get()).get_hashes()).iter() {
+            hashes.push(pry!(hash).to_vec());
+        }
+        Promise::from_future(async move {
+            let _ = channel.send(TerminateOrProceed::Announced(hashes)).await;
+            Ok(())
+        })
+    }
+}
+
+/// Closes the connection once the session ends, whichever way it ends.
+struct SessionGuard {
+    abort_handle: AbortHandle,
+    channel: std::rc::Rc<LocalUnbufferedChannel<TerminateOrProceed>>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.abort_handle.abort();
+        self.channel.close();
+    }
+}
+
+/// Refuses peers on another network and peers that can't prove they hold
+/// the key they present.
+async fn hello(
+    reconcile: &Reconcile::Client,
+    options: &SessionOptions,
+    session: &RwLock<Session>,
+) -> Result<Hello, capnp::Error> {
+    let ours = Hello::ours(options, &*session.read().await);
+    let mut request = reconcile.hello_request();
+    set_hello(request.get().init_hello(), &ours);
+    let response = request.send().promise.await?;
+    let peer = read_hello(response.get()?.get_hello()?)?;
+    if peer.network_id != options.network_id {
+        return Err(capnp::Error::failed(format!(
+            "Network ID mismatch: expected {}, got {}",
+            options.network_id, peer.network_id
+        )));
+    }
+    if peer.claims_identity() && !peer.is_authentic(&*session.read().await) {
+        return Err(capnp::Error::failed(
+            "Peer failed to prove its identity".to_owned(),
+        ));
+    }
+    Ok(peer)
+}
+
+/// Removes the session from the active sessions once it ends.
+struct Registration {
+    options: std::rc::Rc<SessionOptions>,
+    public_key: Vec<u8>,
+    id: Vec<u8>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.options
+            .active_sessions
+            .borrow_mut()
+            .unregister(&self.public_key, &self.id);
+    }
+}
+
+async fn subscribe(
+    reconcile: &Reconcile::Client,
+    channel: std::rc::Rc<LocalUnbufferedChannel<TerminateOrProceed>>,
+) -> Result<(), capnp::Error> {
+    let mut request = reconcile.subscribe_request();
+    request.get().set_listener(
+        InventoryListener::ToClient::new(InventoryListenerServer { channel })
+            .into_client::<capnp_rpc::Server>(),
+    );
+    request.send().promise.await?;
+    Ok(())
+}
+
+fn set_ranges(mut builder: capnp::struct_list::Builder<hash_range::Owned>, ranges: &[Range]) {
+    for (i, range) in ranges.iter().enumerate() {
+        let mut entry = builder.reborrow().get(die_on_error(i.try_into()));
+        entry.set_lower_bound(&range.lower_bound);
+        entry.set_upper_bound(&range.upper_bound);
+    }
+}
+
+fn hashes_in_ranges(inventory: Inventory, ranges: &[Range]) -> Vec<Vec<Vec<u8>>> {
+    ranges
+        .iter()
+        .map(|range| inventory.hashes_in_range(&range.lower_bound, &range.effective_upper_bound()))
+        .collect()
+}
+
+fn is_unimplemented(error: &capnp::Error) -> bool {
+    error.kind == capnp::ErrorKind::Unimplemented
+}
+
+/// Fetches the peer's inventory sketch and decodes the symmetric difference
+/// in one round trip. Returns their and our hashes missing on the other
+/// side, or None if the difference is too large to decode.
+async fn decode_sketch(
+    reconcile: &Reconcile::Client,
+    inventory: Inventory,
+) -> Result<Option<(Vec<Vec<u8>>, Vec<Vec<u8>>)>, capnp::Error> {
+    let mut request = reconcile.sketch_request();
+    request.get().set_cell_count(iblt::DEFAULT_CELL_COUNT);
+    let result = request.send().promise.await?;
+    let mut cells = Vec::new();
+    for cell in result.get()?.get_cells()?.iter() {
+        let key_sum = cell.get_key_sum()?;
+        let mut cell = Cell {
+            count: cell.get_count(),
+            key_sum: [0u8; 64],
+            hash_sum: cell.get_hash_sum(),
+        };
+        if key_sum.len() != cell.key_sum.len() {
+            return Err(capnp::Error::failed(
+                "Peer returned a malformed sketch".to_owned(),
+            ));
+        }
+        cell.key_sum.copy_from_slice(key_sum);
+        cells.push(cell);
+    }
+    let theirs = match InvertibleBloomLookupTable::from_cells(cells) {
+        Some(table) => table,
+        None => {
+            return Err(capnp::Error::failed(
+                "Peer returned a malformed sketch".to_owned(),
+            ))
+        }
+    };
+
+    let mut ours = InvertibleBloomLookupTable::new(iblt::DEFAULT_CELL_COUNT);
+    for hash in task::spawn(async move { inventory.hashes() }).await {
+        ours.insert(&hash);
+    }
+    Ok(ours
+        .difference(&theirs)
+        .map(|(our_hashes, their_hashes)| (their_hashes, our_hashes)))
+}
+
+/// Compares range fingerprints with the peer, recursing only into ranges
+/// that differ, and returns their and our hashes in the mismatching ranges.
+async fn find_differences(
+    reconcile: &Reconcile::Client,
+    inventory: Inventory,
+) -> Result<(Vec<Vec<u8>>, Vec<Vec<u8>>), capnp::Error> {
+    let mut pending = vec![Range::full()];
+    let mut leaves = Vec::new();
+    let mut our_hashes = Vec::new();
+    while !pending.is_empty() {
+        let mut next = Vec::new();
+        for batch in pending.chunks(range_reconcile::MAX_RANGES) {
+            let inventory = inventory.clone();
+            let ranges = batch.to_vec();
+            let our_ranges = task::spawn(async move { hashes_in_ranges(inventory, &ranges) }).await;
+
+            let mut request = reconcile.fingerprints_request();
+            set_ranges(
+                request
+                    .get()
+                    .init_ranges(die_on_error(batch.len().try_into())),
+                batch,
+            );
+            let result = request.send().promise.await?;
+            let their_fingerprints = result.get()?.get_fingerprints()?;
+            if their_fingerprints.len() as usize != batch.len() {
+                return Err(capnp::Error::failed(
+                    "Peer returned the wrong number of range fingerprints".to_owned(),
+                ));
+            }
+
+            for (i, (range, hashes)) in batch.iter().zip(our_ranges).enumerate() {
+                let theirs = their_fingerprints.get(die_on_error(i.try_into()));
+                let their_count = theirs.get_count();
+                if their_count == hashes.len() as u64
+                    && theirs.get_fingerprint()? == &range_reconcile::fingerprint(&hashes)[..]
+                {
+                    continue;
+                }
+                if hashes.len() <= range_reconcile::LEAF_SIZE
+                    || their_count <= range_reconcile::LEAF_SIZE as u64
+                {
+                    leaves.push(range.clone());
+                    our_hashes.extend(hashes);
+                } else {
+                    next.extend(range_reconcile::split(range, &hashes));
+                }
+            }
+        }
+        pending = next;
+    }
+
+    let mut their_hashes = Vec::new();
+    for batch in leaves.chunks(range_reconcile::MAX_RANGES) {
+        let mut request = reconcile.range_hashes_request();
+        set_ranges(
+            request
+                .get()
+                .init_ranges(die_on_error(batch.len().try_into())),
+            batch,
+        );
+        let result = request.send().promise.await?;
+        for hash in result.get()?.get_hashes()?.iter() {
+            their_hashes.push(hash?.to_vec());
+        }
+    }
+    Ok((their_hashes, our_hashes))
+}
+
+fn read_message(
+    message: maybe_message::Reader,
+) -> Result<Option<inventory::Message>, capnp::Error> {
+    match message.which()? {
+        maybe_message::None(()) => Ok(None),
+        maybe_message::Some(message) => {
+            let message = message?;
+            Ok(Some(inventory::Message {
+                payload: message.get_payload()?.to_vec(),
+                nonce: message.get_nonce(),
+                expiration_time: message.get_expiration_time(),
+            }))
+        }
+    }
+}
+
+/// Fetches a batch of messages in a single round trip.
+async fn query_many(
+    reconcile: &Reconcile::Client,
+    hashes: &[Vec<u8>],
+) -> Result<Vec<inventory::Message>, capnp::Error> {
+    let mut request = reconcile.query_many_request();
+    {
+        let mut list = request
+            .get()
+            .init_hashes(die_on_error(hashes.len().try_into()));
+        for (i, hash) in hashes.iter().enumerate() {
+            list.set(die_on_error(i.try_into()), hash);
+        }
+    }
+    let result = request.send().promise.await?;
+    let mut messages = Vec::new();
+    for message in result.get()?.get_messages()?.iter() {
+        if let Some(message) = read_message(message)? {
+            messages.push(message);
+        }
+    }
+    Ok(messages)
+}
+
+/// Fallback for peers without `queryMany`: fetches messages one at a time.
+async fn query_each(
+    reconcile: &Reconcile::Client,
+    hashes: &[Vec<u8>],
+) -> Result<Vec<inventory::Message>, capnp::Error> {
+    let mut messages = Vec::new();
+    for hash in hashes {
+        let mut request = reconcile.query_request();
+        request.get().set_hash(hash);
+        let result = request.send().promise.await?;
+        if let Some(message) = read_message(result.get()?.get_message()?)? {
+            messages.push(message);
+        }
+    }
+    Ok(messages)
+}
+
+fn set_message(mut builder: message::Builder, message: &inventory::Message) {
+    builder.set_payload(&message.payload);
+    builder.set_nonce(message.nonce);
+    builder.set_expiration_time(message.expiration_time);
+}
+
+/// Pushes a batch of messages in a single round trip.
+async fn submit_many(
+    reconcile: &Reconcile::Client,
+    messages: &[inventory::Message],
+) -> Result<(), capnp::Error> {
+    let mut request = reconcile.submit_many_request();
+    {
+        let mut list = request
+            .get()
+            .init_messages(die_on_error(messages.len().try_into()));
+        for (i, message) in messages.iter().enumerate() {
+            set_message(list.reborrow().get(die_on_error(i.try_into())), message);
+        }
+    }
+    let result = request.send().promise.await?;
+    let mut rejected = 0;
+    for outcome in result.get()?.get_outcomes()?.iter() {
+        if let submit_outcome::Rejected(_) = outcome.which()? {
+            rejected += 1;
+        }
+    }
+    if rejected > 0 {
+        log::notice(format!(
+            "Peer rejected {} of {} submitted messages",
+            rejected,
+            messages.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Fallback for peers without `submitMany`: pushes messages one at a time.
+async fn submit_each(
+    reconcile: &Reconcile::Client,
+    messages: &[inventory::Message],
+) -> Result<(), capnp::Error> {
+    for message in messages {
+        let mut request = reconcile.submit_request();
+        set_message(request.get().get_message()?, message);
+        request.send().promise.await?;
+    }
+    Ok(())
+}
+
+/// Fallback for peers without range-based reconciliation: exchanges the
+/// full hash lists.
+async fn exchange_hashes(
+    reconcile: &Reconcile::Client,
+    inventory: Inventory,
+) -> Result<(Vec<Vec<u8>>, Vec<Vec<u8>>), capnp::Error> {
+    let request = reconcile.hashes_request();
+    let result = request.send().promise.await?;
+    let mut their_hashes = Vec::new();
+    for hash in result.get()?.get_hashes()?.iter() {
+        their_hashes.push(hash?.to_vec());
+    }
+
+    let our_hashes = task::spawn(async move { inventory.hashes() }).await;
+    Ok((their_hashes, our_hashes))
+}
+
+/// Compares both inventories in full with the best method the peer supports,
+/// and returns their and our hashes missing on the other side.
+async fn find_all_differences(
+    reconcile: &Reconcile::Client,
+    inventory: Inventory,
+    supports_sketch: &mut bool,
+    supports_ranges: &mut bool,
+) -> Result<(Vec<Vec<u8>>, Vec<Vec<u8>>), capnp::Error> {
+    if *supports_sketch {
+        match decode_sketch(reconcile, inventory.clone()).await {
+            Ok(Some(result)) => return Ok(result),
+            Ok(None) => log::notice("Sketch decoding failed, falling back to a full comparison"),
+            Err(ref error) if is_unimplemented(error) => *supports_sketch = false,
+            Err(error) => return Err(error),
+        }
+    }
+    if *supports_ranges {
+        match find_differences(reconcile, inventory.clone()).await {
+            Ok(result) => return Ok(result),
+            Err(ref error) if is_unimplemented(error) => *supports_ranges = false,
+            Err(error) => return Err(error),
+        }
+    }
+    exchange_hashes(reconcile, inventory).await
+}
+
+/// Peers may report any cursor, but ours are stored as SQLite integers.
+fn to_cursor(cursor: u64) -> i64 {
+    cursor.try_into().unwrap_or(i64::MAX)
+}
+
+/// Returns the peer's hashes inserted after `cursor` and the cursor to pass
+/// next time.
+async fn hashes_since(
+    reconcile: &Reconcile::Client,
+    cursor: u64,
+) -> Result<(Vec<Vec<u8>>, u64), capnp::Error> {
+    let mut request = reconcile.hashes_since_request();
+    request.get().set_cursor(cursor);
+    let response = request.send().promise.await?;
+    let response = response.get()?;
+    let mut hashes = Vec::new();
+    for hash in response.get_hashes()?.iter() {
+        hashes.push(hash?.to_vec());
+    }
+    Ok((hashes, response.get_cursor()))
+}
+
+/// Merges the peer's address book into ours.
+async fn exchange_peers(
+    reconcile: &Reconcile::Client,
+    connection: Pool,
+    source: String,
+    options: &SessionOptions,
+) -> Result<(), capnp::Error> {
+    let response = reconcile.peers_request().send().promise.await?;
+    let mut addresses = Vec::new();
+    for address in response.get()?.get_addresses()?.iter() {
+        addresses.push(address?.to_owned());
+    }
+    let local_scope = options.local_scope;
+    let learned =
+        task::spawn(async move { pex::merge(connection, &source, local_scope, &addresses) }).await;
+    for address in learned {
+        log::notice(format!("Learned about peer {}", address));
+        log::ipc(format_struct(&Message::PeerLearned { address: &address }));
+        let _ = options.learned_peers.unbounded_send(address);
+    }
+    Ok(())
+}
+
+/// Runs a reconciliation session. Both peers serve `Reconcile` and bootstrap
+/// the other's capability over the same connection, so `side` only tells
+/// which end of the two-party network this is. `address` names the peer if
+/// it was dialed.
+#[allow(clippy::too_many_arguments)]
 pub async fn reconcile(
-    stream: async_std::net::TcpStream,
+    mut stream: async_std::net::TcpStream,
+    side: rpc_twoparty_capnp::Side,
+    address: Option<String>,
     connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
     spawner: futures::executor::LocalSpawner,
     reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+    announcer: std::rc::Rc<RwLock<Announcer>>,
+    options: std::rc::Rc<SessionOptions>,
 ) -> Result<(), capnp::Error> {
+    let peer = match &address {
+        Some(address) => address.clone(),
+        None => stream.peer_addr()?.to_string(),
+    };
+    // Inbound peers pick their own source port, so only their IP address
+    // tells them apart.
+    let source = match &address {
+        Some(address) => address.clone(),
+        None => stream.peer_addr()?.ip().to_string(),
+    };
     stream.set_nodelay(true)?;
-    let (reader, writer) = stream.split();
-    let network = twoparty::VatNetwork::new(
-        reader,
-        writer,
-        rpc_twoparty_capnp::Side::Client,
-        Default::default(),
+    let (transport, handshake_hash) = if options.plaintext {
+        (None, Vec::new())
+    } else {
+        let (transport, handshake_hash) =
+            noise::handshake(&mut stream, side, &options.identity, options.network_key).await?;
+        (Some(transport), handshake_hash)
+    };
+
+    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+    let subscriptions = std::rc::Rc::new(RwLock::new(Vec::new()));
+    let session = std::rc::Rc::new(RwLock::new(Session::new(handshake_hash)));
+    let limiter = std::rc::Rc::new(RefCell::new(RateLimiter::new(
+        options.limits,
+        peer.clone(),
+        abort_handle.clone(),
+    )));
+    let server = reconcile_server::new_server(
+        connection.clone(),
+        reconciliation_intent.clone(),
+        announcer.clone(),
+        subscriptions.clone(),
+        options.clone(),
+        session.clone(),
+        limiter,
     );
-    let mut rpc_system = RpcSystem::new(Box::new(network), None);
-    let reconcile: Reconcile::Client = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    let peer_side = match side {
+        rpc_twoparty_capnp::Side::Client => rpc_twoparty_capnp::Side::Server,
+        rpc_twoparty_capnp::Side::Server => rpc_twoparty_capnp::Side::Client,
+    };
+
+    let (reader, writer) = stream.split();
+    let reader = ThrottledReader::new(reader, peer.clone(), options.limits.bytes_per_second);
+    let (reader, writer): (Box<dyn AsyncRead + Unpin>, Box<dyn AsyncWrite + Unpin>) =
+        match transport {
+            Some(transport) => (
+                Box::new(transport.reader(reader)),
+                Box::new(transport.writer(writer)),
+            ),
+            None => (Box::new(reader), Box::new(writer)),
+        };
+    let network = twoparty::VatNetwork::new(reader, writer, side, Default::default());
+    let mut rpc_system = RpcSystem::new(Box::new(network), Some(server.client));
+    let reconcile: Reconcile::Client = rpc_system.bootstrap(peer_side);
+    let rpc_system = Abortable::new(rpc_system, abort_registration);
     let handle = reconciliation_intent.write().await.get_handle();
     let reconciliation_intent1 = reconciliation_intent.clone();
     let reconciliation_intent2 = reconciliation_intent.clone();
-
-    #[derive(Debug)]
-    enum TerminateOrProceed {
-        Terminate(Result<(), capnp::Error>),
-        Proceed,
-    };
+    let announcer1 = announcer.clone();
 
     let channel = std::rc::Rc::new(LocalUnbufferedChannel::new());
     let channel1 = channel.clone();
     let channel2 = channel.clone();
+    let _guard = SessionGuard {
+        abort_handle: abort_handle.clone(),
+        channel: channel.clone(),
+    };
 
     die_on_error(
         spawner.spawn_local_obj(
             Box::new(async move {
-                if let Err(error) = rpc_system.await {
-                    if let Err(_) = channel1
-                        .send(TerminateOrProceed::Terminate(Err(error)))
-                        .await
-                    {}
-                } else {
-                    if let Err(_) = channel1.send(TerminateOrProceed::Terminate(Ok(()))).await {}
+                match rpc_system.await {
+                    Ok(Err(error)) => {
+                        let _ = channel1
+                            .send(TerminateOrProceed::Terminate(Err(error)))
+                            .await;
+                    }
+                    Ok(Ok(())) => {
+                        let _ = channel1.send(TerminateOrProceed::Terminate(Ok(()))).await;
+                    }
+                    Err(Aborted) => {
+                        let _ = channel1
+                            .send(TerminateOrProceed::Terminate(Err(
+                                capnp::Error::disconnected("Session closed".to_owned()),
+                            )))
+                            .await;
+                    }
                 }
                 reconciliation_intent1.write().await.drop_handle(handle);
+                for subscription in subscriptions.read().await.iter() {
+                    announcer1.write().await.unsubscribe(*subscription);
+                }
             })
             .into(),
         ),
@@ -74,78 +592,270 @@ pub async fn reconcile(
         ),
     );
 
+    let mut _registration = None;
+    let node = match hello(&reconcile, &options, &session).await {
+        Ok(their_hello) => {
+            if their_hello.protocol_version != PROTOCOL_VERSION {
+                log::notice(format!(
+                    "Peer runs protocol version {} (software version {}), ours is {}",
+                    their_hello.protocol_version, their_hello.software_version, PROTOCOL_VERSION
+                ));
+            }
+            if !their_hello.public_key.is_empty() {
+                if their_hello.public_key[..] == options.identity.public_key[..] {
+                    return Err(capnp::Error::failed("Connected to ourselves".to_owned()));
+                }
+                let id = identity::session_id(&session.read().await.nonce, &their_hello.nonce);
+                if !options.active_sessions.borrow_mut().register(
+                    &their_hello.public_key,
+                    id.clone(),
+                    abort_handle,
+                ) {
+                    return Err(capnp::Error::failed(format!(
+                        "Already connected to node {}",
+                        base64::encode(&their_hello.public_key)
+                    )));
+                }
+                log::notice(format!(
+                    "Peer {} is node {}",
+                    peer,
+                    base64::encode(&their_hello.public_key)
+                ));
+                _registration = Some(Registration {
+                    options: options.clone(),
+                    public_key: their_hello.public_key.clone(),
+                    id,
+                });
+            }
+            // Cursors belong to authenticated nodes rather than addresses,
+            // which another node may take over.
+            let node = Some(their_hello.public_key.clone()).filter(|key| !key.is_empty());
+            let mut session = session.write().await;
+            session.peer = Some(their_hello);
+            session.authenticated = true;
+            session.settled.set();
+            node
+        }
+        Err(ref error) if is_unimplemented(error) => {
+            // Peers that predate the handshake know nothing of networks, so
+            // they only belong on the default one. They are served `hashes`,
+            // `query` and `submit`, and only those are called on them.
+            if options.network_id != DEFAULT_NETWORK_ID {
+                return Err(capnp::Error::failed(format!(
+                    "Peer predates the handshake, so it can't be on network {}",
+                    options.network_id
+                )));
+            }
+            log::notice(format!("Peer {} predates the handshake", peer));
+            let mut session = session.write().await;
+            session.legacy = true;
+            session.settled.set();
+            None
+        }
+        Err(error) => return Err(error),
+    };
+
+    if session.read().await.supports(FEATURE_PEERS) {
+        match exchange_peers(&reconcile, connection.clone(), source, &options).await {
+            Ok(()) => {}
+            Err(ref error) if is_unimplemented(error) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    if session.read().await.supports(FEATURE_SUBSCRIBE) {
+        match subscribe(&reconcile, channel.clone()).await {
+            Ok(()) => {}
+            Err(ref error) if is_unimplemented(error) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    let mut supports_sketch = session.read().await.supports(FEATURE_SKETCH);
+    let mut supports_ranges = session.read().await.supports(FEATURE_RANGES);
+    let mut supports_query_many = session.read().await.supports(FEATURE_QUERY_MANY);
+    let mut supports_submit_many = session.read().await.supports(FEATURE_SUBMIT_MANY);
+    let mut supports_hashes_since = session.read().await.supports(FEATURE_HASHES_SINCE);
+    let mut cursor = match &node {
+        Some(node) if supports_hashes_since => {
+            let inventory = options.inventory.clone();
+            let node = node.clone();
+            task::spawn(async move { inventory.peer_cursor(&node) }).await
+        }
+        _ => None,
+    };
+    let mut announced = None;
     loop {
-        let request = reconcile.hashes_request();
-        let result = request.send().promise.await?;
-        let their_hashes = result.get()?.get_hashes()?;
-
-        let mut hash_set = HashSet::<Vec<u8>>::new();
-        let connection1 = connection.clone();
-        let connection2 = connection.clone();
-        for i in 0..their_hashes.len() {
-            let hash1 = std::sync::Arc::new(their_hashes.get(i)?.to_vec());
-            let hash2 = hash1.clone();
-            hash_set.insert(hash1.to_vec());
-            let connection1 = connection1.clone();
-            let connection2 = connection2.clone();
-            if !task::spawn(async move { inventory::exists(connection1, &hash1) }).await {
-                let mut query_request = reconcile.query_request();
-                query_request.get().set_hash(&hash2);
-                let result = query_request.send().promise.await?;
-                let message = result.get()?.get_message()?;
-                if let crate::reconcile_capnp::maybe_message::Some(message) = message.which()? {
-                    let message = message?;
-                    let payload = message.get_payload()?.to_vec();
-                    let nonce = message.get_nonce();
-                    let expiration_time = message.get_expiration_time();
-                    if crate::proof_of_work::verify(&payload, nonce, expiration_time) {
-                        task::spawn(async move {
-                            inventory::insert(connection2, &payload, nonce, expiration_time);
-                        })
-                        .await;
-                        reconciliation_intent
-                            .read()
-                            .await
-                            .broadcast_to_others(handle);
+        let mut next_cursor = None;
+        // Messages inserted after this point in our sequence are pushed on
+        // top of what the comparison found.
+        let mut pushed_up_to = None;
+        let (their_hashes, our_hashes) = if let Some(hashes) = announced.take() {
+            // Announced hashes only need to be fetched.
+            (hashes, Vec::new())
+        } else {
+            let mut incremental = None;
+            if let Some(current) = cursor.filter(|cursor| {
+                inventory::now() < cursor.reconciled_at + FULL_RECONCILIATION_INTERVAL
+            }) {
+                let (hashes, theirs) = hashes_since(&reconcile, current.theirs as u64).await?;
+                let theirs = to_cursor(theirs);
+                if theirs < current.theirs {
+                    log::notice(format!(
+                        "Insertion sequence of peer {} went backwards, reconciling in full",
+                        peer
+                    ));
+                    cursor = None;
+                } else {
+                    next_cursor = Some(PeerCursor { theirs, ..current });
+                    pushed_up_to = Some(current.ours);
+                    incremental = Some((hashes, Vec::new()));
+                }
+            }
+            match incremental {
+                Some(hashes) => hashes,
+                None => {
+                    let inventory = options.inventory.clone();
+                    let ours = task::spawn(async move { inventory.hashes_since(i64::MAX).1 }).await;
+                    pushed_up_to = Some(ours);
+                    if supports_hashes_since {
+                        match hashes_since(&reconcile, u64::MAX).await {
+                            Ok((_, theirs)) => {
+                                next_cursor = Some(PeerCursor {
+                                    theirs: to_cursor(theirs),
+                                    ours,
+                                    reconciled_at: inventory::now(),
+                                })
+                            }
+                            Err(ref error) if is_unimplemented(error) => {
+                                supports_hashes_since = false
+                            }
+                            Err(error) => return Err(error),
+                        }
+                    }
+                    find_all_differences(
+                        &reconcile,
+                        options.inventory.clone(),
+                        &mut supports_sketch,
+                        &mut supports_ranges,
+                    )
+                    .await?
+                }
+            }
+        };
+
+        let hash_set: HashSet<Vec<u8>> = their_hashes.iter().cloned().collect();
+        let mut missing = Vec::new();
+        for hash in their_hashes {
+            let hash = std::sync::Arc::new(hash);
+            let hash1 = hash.clone();
+            let inventory = options.inventory.clone();
+            if !task::spawn(async move { inventory.exists(&hash1) }).await {
+                missing.push(hash.to_vec());
+            }
+        }
+
+        for batch in missing.chunks(options.batch_size) {
+            let messages = if supports_query_many {
+                match query_many(&reconcile, batch).await {
+                    Ok(messages) => messages,
+                    Err(ref error) if is_unimplemented(error) => {
+                        supports_query_many = false;
+                        query_each(&reconcile, batch).await?
+                    }
+                    Err(error) => return Err(error),
+                }
+            } else {
+                query_each(&reconcile, batch).await?
+            };
+            let mut inserted = Vec::new();
+            for message in messages {
+                // Messages we couldn't pass on aren't kept either.
+                if message.payload.len() > options.limits.max_payload_size {
+                    continue;
+                }
+                if crate::proof_of_work::verify(
+                    &message.payload,
+                    message.nonce,
+                    message.expiration_time,
+                    options.proof_of_work_key.as_ref().map(|key| &key[..]),
+                ) {
+                    let hash = message_hash(&message.payload, message.expiration_time).to_vec();
+                    let inventory = options.inventory.clone();
+                    let insertion = task::spawn(async move {
+                        inventory.insert(&message.payload, message.nonce, message.expiration_time)
+                    })
+                    .await;
+                    if insertion != Insertion::Inserted {
+                        continue;
                     }
+                    inserted.push(hash);
+                    reconciliation_intent
+                        .read()
+                        .await
+                        .broadcast_to_others(handle);
                 }
             }
+            announcer.read().await.announce(&inserted);
         }
 
-        {
-            let connection = connection.clone();
-            let connection1 = connection.clone();
-            let channel = inventory::hashes(connection);
-
-            while let Some(hash) = channel.clone().recv().await {
-                if !hash_set.contains(&hash) {
-                    let connection1 = connection1.clone();
-                    let message =
-                        match task::spawn(async move { inventory::retrieve(connection1, &hash) })
-                            .await
-                        {
-                            Some(message) => message,
-                            None => continue,
-                        };
-                    let mut submit_request = reconcile.submit_request();
-                    submit_request
-                        .get()
-                        .get_message()?
-                        .set_payload(&message.payload);
-                    submit_request.get().get_message()?.set_nonce(message.nonce);
-                    submit_request
-                        .get()
-                        .get_message()?
-                        .set_expiration_time(message.expiration_time);
-                    submit_request.send().promise.await?;
+        let mut our_hashes = our_hashes;
+        if let Some(pushed_up_to) = pushed_up_to {
+            let inventory = options.inventory.clone();
+            let (hashes, ours) =
+                task::spawn(async move { inventory.hashes_since(pushed_up_to) }).await;
+            our_hashes.extend(hashes);
+            if let Some(next_cursor) = next_cursor.as_mut() {
+                next_cursor.ours = ours;
+            }
+        }
+        // What was just fetched from the peer isn't pushed back.
+        let mut seen = hash_set;
+        let outgoing: Vec<Vec<u8>> = our_hashes
+            .into_iter()
+            .filter(|hash| seen.insert(hash.clone()))
+            .collect();
+        for batch in outgoing.chunks(options.batch_size) {
+            let inventory = options.inventory.clone();
+            let batch = batch.to_vec();
+            let max_payload_size = options.limits.max_payload_size;
+            // Peers reject oversized payloads, so they are never sent. They
+            // can only have been stored before the limit was lowered.
+            let messages = task::spawn(async move {
+                batch
+                    .iter()
+                    .filter_map(|hash| inventory.retrieve(hash))
+                    .filter(|message| message.payload.len() <= max_payload_size)
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            if messages.is_empty() {
+                continue;
+            }
+            if supports_submit_many {
+                match submit_many(&reconcile, &messages).await {
+                    Ok(()) => continue,
+                    Err(ref error) if is_unimplemented(error) => supports_submit_many = false,
+                    Err(error) => return Err(error),
                 }
             }
+            submit_each(&reconcile, &messages).await?;
+        }
+
+        if let (Some(next_cursor), Some(node)) = (next_cursor, node.clone()) {
+            cursor = Some(next_cursor);
+            let inventory = options.inventory.clone();
+            task::spawn(async move { inventory.set_peer_cursor(&node, next_cursor) }).await;
         }
 
         match channel.receive().await {
             Some(terminate_or_proceed) => match terminate_or_proceed {
                 TerminateOrProceed::Terminate(result) => return result,
                 TerminateOrProceed::Proceed => continue,
+                TerminateOrProceed::Announced(hashes) => {
+                    announced = Some(hashes);
+                    continue;
+                }
             },
             None => return Ok(()),
         }
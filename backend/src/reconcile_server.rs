@@ -1,31 +1,136 @@
+use crate::announcer::Announcer;
 use crate::die_on_error::die_on_error;
-use crate::inventory;
+use crate::iblt::{self, InvertibleBloomLookupTable};
+use crate::identity::PUBLIC_KEY_LENGTH;
+use crate::inventory::{self, Insertion};
 use crate::message_hash::message_hash;
 use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::peers;
+use crate::pex;
+use crate::proof_of_work::{get_expected_target2, verify};
+use crate::protocol::{read_hello, set_hello, Hello, Session, SessionOptions};
+use crate::range_reconcile::{self, Range};
+use crate::rate_limit::{Admission, RateLimiter};
 use crate::reconcile_capnp::reconcile as Reconcile;
+use crate::reconcile_capnp::{hash_range, RejectionReason};
 use async_std::sync::RwLock;
 use async_std::task;
 use capnp::capability::Promise;
 use capnp::Error;
-use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
-use futures::AsyncReadExt;
+use capnp_rpc::pry;
 use r2d2_sqlite::SqliteConnectionManager;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::convert::TryInto;
+use std::future::Future;
 struct ReconcileRPCServer {
     connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
     reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+    announcer: std::rc::Rc<RwLock<Announcer>>,
+    subscriptions: std::rc::Rc<RwLock<Vec<u128>>>,
+    options: std::rc::Rc<SessionOptions>,
+    session: std::rc::Rc<RwLock<Session>>,
+    limiter: std::rc::Rc<RefCell<RateLimiter>>,
 }
 
 impl ReconcileRPCServer {
     fn new(
         connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
         reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+        announcer: std::rc::Rc<RwLock<Announcer>>,
+        subscriptions: std::rc::Rc<RwLock<Vec<u128>>>,
+        options: std::rc::Rc<SessionOptions>,
+        session: std::rc::Rc<RwLock<Session>>,
+        limiter: std::rc::Rc<RefCell<RateLimiter>>,
     ) -> ReconcileRPCServer {
         ReconcileRPCServer {
             connection,
             reconciliation_intent,
+            announcer,
+            subscriptions,
+            options,
+            session,
+            limiter,
         }
     }
+
+    /// Runs `future` once the peer has answered our hello and is within its
+    /// limits.
+    fn limited<F>(&self, future: F) -> Promise<(), Error>
+    where
+        F: Future<Output = Result<(), Error>> + 'static,
+    {
+        self.admitted(false, future)
+    }
+
+    /// Same as `limited`, except peers that predate the handshake are served
+    /// too. Only for the calls they know.
+    fn limited_or_legacy<F>(&self, future: F) -> Promise<(), Error>
+    where
+        F: Future<Output = Result<(), Error>> + 'static,
+    {
+        self.admitted(true, future)
+    }
+
+    fn admitted<F>(&self, legacy_allowed: bool, future: F) -> Promise<(), Error>
+    where
+        F: Future<Output = Result<(), Error>> + 'static,
+    {
+        let session = self.session.clone();
+        self.throttled(async move {
+            let settled = session.read().await.settled.clone();
+            settled.wait().await;
+            let admitted = {
+                let session = session.read().await;
+                session.authenticated || (legacy_allowed && session.legacy)
+            };
+            if !admitted {
+                return Err(Error::failed(
+                    "Calls are refused until the handshake succeeds".to_owned(),
+                ));
+            }
+            future.await
+        })
+    }
+
+    /// Runs `future` once the peer is within its limits.
+    fn throttled<F>(&self, future: F) -> Promise<(), Error>
+    where
+        F: Future<Output = Result<(), Error>> + 'static,
+    {
+        let limiter = self.limiter.clone();
+        Promise::from_future(async move {
+            let admission = limiter.borrow_mut().admit();
+            match admission {
+                Admission::Proceed => {}
+                Admission::Throttle(delay) => task::sleep(delay).await,
+                Admission::Disconnect => {
+                    return Err(Error::disconnected("Too many concurrent calls".to_owned()))
+                }
+            }
+            let result = future.await;
+            limiter.borrow_mut().finish();
+            result
+        })
+    }
+}
+
+fn read_ranges(ranges: capnp::struct_list::Reader<hash_range::Owned>) -> Result<Vec<Range>, Error> {
+    if ranges.len() as usize > range_reconcile::MAX_RANGES {
+        return Err(Error::failed(format!(
+            "Asked about {} ranges, the limit is {}",
+            ranges.len(),
+            range_reconcile::MAX_RANGES
+        )));
+    }
+    let mut result = Vec::new();
+    for range in ranges.iter() {
+        result.push(Range {
+            lower_bound: range.get_lower_bound()?.to_vec(),
+            upper_bound: range.get_upper_bound()?.to_vec(),
+        });
+    }
+    Ok(result)
 }
 
 impl Reconcile::Server for ReconcileRPCServer {
@@ -34,23 +139,14 @@ impl Reconcile::Server for ReconcileRPCServer {
         _params: Reconcile::HashesParams,
         mut results: Reconcile::HashesResults,
     ) -> Promise<(), Error> {
-        let connection = self.connection.clone();
-        Promise::from_future(async move {
-            let hashes1 = std::sync::Arc::new(std::sync::Mutex::new(Vec::<Vec<u8>>::new()));
-            let hashes2 = hashes1.clone();
-            task::spawn(async move {
-                let channel = inventory::hashes(connection);
-                while let Some(hash) = channel.recv().await {
-                    die_on_error(hashes1.lock()).push(hash);
-                }
-            })
-            .await;
-            let length: u32 = die_on_error(die_on_error(hashes2.lock()).len().try_into());
-            let mut result = results.get().init_hashes(length);
-
-            for i in 0..length {
-                let vector_index: usize = die_on_error(i.try_into());
-                result.set(i, &die_on_error(hashes2.lock())[vector_index]);
+        let inventory = self.options.inventory.clone();
+        self.limited_or_legacy(async move {
+            let hashes = task::spawn(async move { inventory.hashes() }).await;
+            let mut result = results
+                .get()
+                .init_hashes(die_on_error(hashes.len().try_into()));
+            for (i, hash) in hashes.iter().enumerate() {
+                result.set(die_on_error(i.try_into()), hash);
             }
             Ok(())
         })
@@ -61,17 +157,16 @@ impl Reconcile::Server for ReconcileRPCServer {
         params: Reconcile::QueryParams,
         mut results: Reconcile::QueryResults,
     ) -> Promise<(), Error> {
-        let connection = self.connection.clone();
-        Promise::from_future(async move {
+        let inventory = self.options.inventory.clone();
+        self.limited_or_legacy(async move {
             let hash = params.get()?.get_hash()?.to_vec();
-            let message =
-                match task::spawn(async move { inventory::retrieve(connection, &hash) }).await {
-                    Some(message) => message,
-                    None => {
-                        results.get().get_message()?.set_none(());
-                        return Ok(());
-                    }
-                };
+            let message = match task::spawn(async move { inventory.retrieve(&hash) }).await {
+                Some(message) => message,
+                None => {
+                    results.get().get_message()?.set_none(());
+                    return Ok(());
+                }
+            };
             let mut result = results.get().get_message()?.init_some();
             result.set_payload(&message.payload);
             result.set_nonce(message.nonce);
@@ -80,55 +175,384 @@ impl Reconcile::Server for ReconcileRPCServer {
         })
     }
 
+    fn query_many(
+        &mut self,
+        params: Reconcile::QueryManyParams,
+        mut results: Reconcile::QueryManyResults,
+    ) -> Promise<(), Error> {
+        let inventory = self.options.inventory.clone();
+        self.limited(async move {
+            let mut hashes = Vec::new();
+            for hash in params.get()?.get_hashes()?.iter() {
+                hashes.push(hash?.to_vec());
+            }
+            let messages = task::spawn(async move {
+                hashes
+                    .iter()
+                    .map(|hash| inventory.retrieve(hash))
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            let mut result = results
+                .get()
+                .init_messages(die_on_error(messages.len().try_into()));
+            for (i, message) in messages.iter().enumerate() {
+                let mut entry = result.reborrow().get(die_on_error(i.try_into()));
+                match message {
+                    Some(message) => {
+                        let mut entry = entry.init_some();
+                        entry.set_payload(&message.payload);
+                        entry.set_nonce(message.nonce);
+                        entry.set_expiration_time(message.expiration_time);
+                    }
+                    None => entry.set_none(()),
+                }
+            }
+            Ok(())
+        })
+    }
+
     fn submit(
         &mut self,
         params: Reconcile::SubmitParams,
         _results: Reconcile::SubmitResults,
     ) -> Promise<(), Error> {
-        let connection1 = self.connection.clone();
-        let connection2 = self.connection.clone();
+        let inventory1 = self.options.inventory.clone();
+        let inventory2 = self.options.inventory.clone();
         let reconciliation_intent = self.reconciliation_intent.clone();
+        let announcer = self.announcer.clone();
         let message = pry!(pry!(params.get()).get_message());
         let payload = pry!(message.get_payload()).to_vec();
+        if !self.limiter.borrow().check_payload_size(payload.len()) {
+            return Promise::ok(());
+        }
         let nonce = message.get_nonce();
         let expiration_time = message.get_expiration_time();
-        Promise::from_future(async move {
-            let hash1 = std::sync::Arc::new(message_hash(&payload, expiration_time).to_vec());
-            let message_exists =
-                task::spawn(async move { inventory::exists(connection1, &hash1) }).await;
+        let proof_of_work_key = self.options.proof_of_work_key;
+        self.limited_or_legacy(async move {
+            let hash = message_hash(&payload, expiration_time).to_vec();
+            let hash1 = std::sync::Arc::new(hash.clone());
+            let message_exists = task::spawn(async move { inventory1.exists(&hash1) }).await;
 
-            let proof_of_work_valid =
-                crate::proof_of_work::verify(&payload, nonce, expiration_time);
+            let proof_of_work_valid = crate::proof_of_work::verify(
+                &payload,
+                nonce,
+                expiration_time,
+                proof_of_work_key.as_ref().map(|key| &key[..]),
+            );
 
             if !message_exists && proof_of_work_valid {
-                task::spawn(async move {
-                    inventory::insert(connection2, &payload, nonce, expiration_time);
-                })
-                .await;
+                let insertion =
+                    task::spawn(async move { inventory2.insert(&payload, nonce, expiration_time) })
+                        .await;
+                if insertion != Insertion::Inserted {
+                    return Ok(());
+                }
                 let cloned = reconciliation_intent.clone();
                 cloned.read().await.broadcast();
+                announcer.read().await.announce(&[hash]);
+            }
+            Ok(())
+        })
+    }
+
+    fn submit_many(
+        &mut self,
+        params: Reconcile::SubmitManyParams,
+        mut results: Reconcile::SubmitManyResults,
+    ) -> Promise<(), Error> {
+        let inventory = self.options.inventory.clone();
+        let reconciliation_intent = self.reconciliation_intent.clone();
+        let announcer = self.announcer.clone();
+        let limiter = self.limiter.clone();
+        let proof_of_work_key = self.options.proof_of_work_key;
+        self.limited(async move {
+            let mut messages = Vec::new();
+            for message in params.get()?.get_messages()?.iter() {
+                let payload = message.get_payload()?;
+                // Oversized payloads aren't even copied.
+                messages.push(if limiter.borrow().check_payload_size(payload.len()) {
+                    Some(inventory::Message {
+                        payload: payload.to_vec(),
+                        nonce: message.get_nonce(),
+                        expiration_time: message.get_expiration_time(),
+                    })
+                } else {
+                    None
+                });
+            }
+            let (outcomes, accepted_hashes) = task::spawn(async move {
+                let mut outcomes = Vec::new();
+                let mut accepted = Vec::new();
+                // Where each accepted message's outcome goes, with its hash.
+                let mut slots = Vec::new();
+                let mut accepted_hashes = Vec::new();
+                let mut seen = HashSet::new();
+                for message in messages {
+                    let message = match message {
+                        Some(message) => message,
+                        None => {
+                            outcomes.push(Some(RejectionReason::TooLarge));
+                            continue;
+                        }
+                    };
+                    let hash = message_hash(&message.payload, message.expiration_time).to_vec();
+                    let outcome = if !seen.insert(hash.clone()) || inventory.exists(&hash) {
+                        Some(RejectionReason::Duplicate)
+                    } else if get_expected_target2(&message.payload, message.expiration_time)
+                        .is_none()
+                    {
+                        Some(RejectionReason::Expired)
+                    } else if !verify(
+                        &message.payload,
+                        message.nonce,
+                        message.expiration_time,
+                        proof_of_work_key.as_ref().map(|key| &key[..]),
+                    ) {
+                        Some(RejectionReason::InvalidProofOfWork)
+                    } else {
+                        accepted.push(message);
+                        slots.push((outcomes.len(), hash));
+                        None
+                    };
+                    outcomes.push(outcome);
+                }
+                if !accepted.is_empty() {
+                    let insertions = inventory.insert_many(&accepted);
+                    for ((position, hash), insertion) in slots.into_iter().zip(insertions) {
+                        match insertion {
+                            Insertion::Inserted => accepted_hashes.push(hash),
+                            Insertion::Duplicate => {
+                                outcomes[position] = Some(RejectionReason::Duplicate)
+                            }
+                            Insertion::Full => {
+                                outcomes[position] = Some(RejectionReason::InventoryFull)
+                            }
+                        }
+                    }
+                }
+                (outcomes, accepted_hashes)
+            })
+            .await;
+
+            if !accepted_hashes.is_empty() {
+                reconciliation_intent.read().await.broadcast();
+                announcer.read().await.announce(&accepted_hashes);
+            }
+            let mut result = results
+                .get()
+                .init_outcomes(die_on_error(outcomes.len().try_into()));
+            for (i, outcome) in outcomes.iter().enumerate() {
+                let mut entry = result.reborrow().get(die_on_error(i.try_into()));
+                match outcome {
+                    Some(reason) => entry.set_rejected(*reason),
+                    None => entry.set_accepted(()),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn fingerprints(
+        &mut self,
+        params: Reconcile::FingerprintsParams,
+        mut results: Reconcile::FingerprintsResults,
+    ) -> Promise<(), Error> {
+        let inventory = self.options.inventory.clone();
+        self.limited(async move {
+            let ranges = read_ranges(params.get()?.get_ranges()?)?;
+            let summaries = task::spawn(async move {
+                ranges
+                    .iter()
+                    .map(|range| {
+                        let hashes = inventory
+                            .hashes_in_range(&range.lower_bound, &range.effective_upper_bound());
+                        (hashes.len(), range_reconcile::fingerprint(&hashes))
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            let mut result = results
+                .get()
+                .init_fingerprints(die_on_error(summaries.len().try_into()));
+            for (i, (count, fingerprint)) in summaries.iter().enumerate() {
+                let mut entry = result.reborrow().get(die_on_error(i.try_into()));
+                entry.set_count(die_on_error((*count).try_into()));
+                entry.set_fingerprint(fingerprint);
+            }
+            Ok(())
+        })
+    }
+
+    fn range_hashes(
+        &mut self,
+        params: Reconcile::RangeHashesParams,
+        mut results: Reconcile::RangeHashesResults,
+    ) -> Promise<(), Error> {
+        let inventory = self.options.inventory.clone();
+        self.limited(async move {
+            let ranges = read_ranges(params.get()?.get_ranges()?)?;
+            let hashes = task::spawn(async move {
+                let mut hashes = Vec::new();
+                for range in ranges {
+                    hashes.extend(
+                        inventory
+                            .hashes_in_range(&range.lower_bound, &range.effective_upper_bound()),
+                    );
+                }
+                hashes
+            })
+            .await;
+            let mut result = results
+                .get()
+                .init_hashes(die_on_error(hashes.len().try_into()));
+            for (i, hash) in hashes.iter().enumerate() {
+                result.set(die_on_error(i.try_into()), hash);
+            }
+            Ok(())
+        })
+    }
+
+    fn sketch(
+        &mut self,
+        params: Reconcile::SketchParams,
+        mut results: Reconcile::SketchResults,
+    ) -> Promise<(), Error> {
+        let inventory = self.options.inventory.clone();
+        self.limited(async move {
+            let cell_count = std::cmp::min(params.get()?.get_cell_count(), iblt::MAX_CELL_COUNT);
+            let table = task::spawn(async move {
+                let mut table = InvertibleBloomLookupTable::new(cell_count);
+                for hash in inventory.hashes() {
+                    table.insert(&hash);
+                }
+                table
+            })
+            .await;
+            let cells = table.cells();
+            let mut result = results
+                .get()
+                .init_cells(die_on_error(cells.len().try_into()));
+            for (i, cell) in cells.iter().enumerate() {
+                let mut entry = result.reborrow().get(die_on_error(i.try_into()));
+                entry.set_count(cell.count);
+                entry.set_key_sum(&cell.key_sum);
+                entry.set_hash_sum(cell.hash_sum);
             }
             Ok(())
         })
     }
+
+    fn subscribe(
+        &mut self,
+        params: Reconcile::SubscribeParams,
+        _results: Reconcile::SubscribeResults,
+    ) -> Promise<(), Error> {
+        let announcer = self.announcer.clone();
+        let subscriptions = self.subscriptions.clone();
+        let listener = pry!(pry!(params.get()).get_listener());
+        self.limited(async move {
+            let subscription = announcer.write().await.subscribe(listener);
+            subscriptions.write().await.push(subscription);
+            Ok(())
+        })
+    }
+
+    fn hashes_since(
+        &mut self,
+        params: Reconcile::HashesSinceParams,
+        mut results: Reconcile::HashesSinceResults,
+    ) -> Promise<(), Error> {
+        let inventory = self.options.inventory.clone();
+        // Cursors past the end of our sequence only fetch the current cursor.
+        let cursor = pry!(params.get())
+            .get_cursor()
+            .try_into()
+            .unwrap_or(i64::MAX);
+        self.limited(async move {
+            let (hashes, next_cursor) =
+                task::spawn(async move { inventory.hashes_since(cursor) }).await;
+            let mut builder = results.get();
+            builder.set_cursor(die_on_error(next_cursor.try_into()));
+            let mut list = builder.init_hashes(die_on_error(hashes.len().try_into()));
+            for (i, hash) in hashes.iter().enumerate() {
+                list.set(die_on_error(i.try_into()), hash);
+            }
+            Ok(())
+        })
+    }
+
+    fn peers(
+        &mut self,
+        _params: Reconcile::PeersParams,
+        mut results: Reconcile::PeersResults,
+    ) -> Promise<(), Error> {
+        let connection = self.connection.clone();
+        self.limited(async move {
+            let addresses =
+                task::spawn(async move { peers::sample(connection, pex::SAMPLE_SIZE) }).await;
+            let mut list = results
+                .get()
+                .init_addresses(die_on_error(addresses.len().try_into()));
+            for (i, address) in addresses.iter().enumerate() {
+                list.set(die_on_error(i.try_into()), address);
+            }
+            Ok(())
+        })
+    }
+
+    fn hello(
+        &mut self,
+        params: Reconcile::HelloParams,
+        mut results: Reconcile::HelloResults,
+    ) -> Promise<(), Error> {
+        let peer = pry!(read_hello(pry!(pry!(params.get()).get_hello())));
+        if peer.network_id != self.options.network_id {
+            return Promise::err(Error::failed(format!(
+                "Network ID mismatch: expected {}, got {}",
+                self.options.network_id, peer.network_id
+            )));
+        }
+        if peer.claims_identity() && peer.public_key.len() != PUBLIC_KEY_LENGTH {
+            return Promise::err(Error::failed(
+                "Peer advertised identity support without a valid key".to_owned(),
+            ));
+        }
+        let options = self.options.clone();
+        let session = self.session.clone();
+        // The peer's own hello proves nothing about its key, so only its
+        // reply to ours is kept.
+        self.throttled(async move {
+            let session = session.read().await;
+            set_hello(
+                results.get().init_hello(),
+                &Hello::reply(&options, &session, &peer),
+            );
+            Ok(())
+        })
+    }
 }
 
-pub async fn init_server(
-    stream: async_std::net::TcpStream,
+/// Creates the capability served to a peer. Listeners the peer subscribes are
+/// recorded in `subscriptions` so they can be dropped once the connection
+/// closes.
+pub fn new_server(
     connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
     reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
-) -> Result<(), capnp::Error> {
-    let reconcile =
-        Reconcile::ToClient::new(ReconcileRPCServer::new(connection, reconciliation_intent))
-            .into_client::<capnp_rpc::Server>();
-    stream.set_nodelay(true)?;
-    let (reader, writer) = stream.split();
-    let network = twoparty::VatNetwork::new(
-        reader,
-        writer,
-        rpc_twoparty_capnp::Side::Server,
-        Default::default(),
-    );
-    let rpc_system = RpcSystem::new(Box::new(network), Some(reconcile.clone().client));
-    rpc_system.await
+    announcer: std::rc::Rc<RwLock<Announcer>>,
+    subscriptions: std::rc::Rc<RwLock<Vec<u128>>>,
+    options: std::rc::Rc<SessionOptions>,
+    session: std::rc::Rc<RwLock<Session>>,
+    limiter: std::rc::Rc<RefCell<RateLimiter>>,
+) -> Reconcile::Client {
+    Reconcile::ToClient::new(ReconcileRPCServer::new(
+        connection,
+        reconciliation_intent,
+        announcer,
+        subscriptions,
+        options,
+        session,
+        limiter,
+    ))
+    .into_client::<capnp_rpc::Server>()
 }
@@ -0,0 +1,164 @@
+use crate::identity::{self, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use serde::{Deserialize, Serialize};
+
+type Pool = std::sync::Arc<r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>>;
+
+// Envelope layout: magic, version, signer public key, signature, content type
+// length, content type, then the body. The magic keeps arbitrary payloads
+// from being mistaken for envelopes with bad signatures.
+const MAGIC: &[u8] = b"CSSIG";
+const VERSION: u8 = 1;
+const SIGNATURE_OFFSET: usize = MAGIC.len() + 1 + PUBLIC_KEY_LENGTH;
+const HEADER_LENGTH: usize = SIGNATURE_OFFSET + SIGNATURE_LENGTH + 1;
+const MAX_CONTENT_TYPE_LENGTH: usize = 255;
+
+/// What the signer signs. The context string keeps these signatures from
+/// being passed off as signatures over anything else.
+fn signed_data(signer: &[u8], content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut data = b"contrasleuth signed envelope".to_vec();
+    data.push(VERSION);
+    data.extend_from_slice(signer);
+    data.push(content_type.len() as u8);
+    data.extend_from_slice(content_type.as_bytes());
+    data.extend_from_slice(body);
+    data
+}
+
+/// Ed25519 key pair that envelopes are signed with. It is kept apart from the
+/// node identity, whose seed also yields the Noise static key, so signed
+/// envelopes don't tie their author to the node's network identity.
+pub struct SigningKey {
+    pub public_key: [u8; PUBLIC_KEY_LENGTH],
+    secret_key: [u8; 64],
+}
+
+impl SigningKey {
+    /// Generates the key pair on first start.
+    pub fn load_or_generate(pool: Pool) -> SigningKey {
+        let seed = identity::load_or_generate_seed(
+            pool,
+            include_str!("../sql/D. Identity/3. Retrieve signing key.sql"),
+            include_str!("../sql/D. Identity/4. Put signing key.sql"),
+        );
+        let (secret_key, public_key) = crypto::ed25519::keypair(&seed);
+        SigningKey {
+            public_key,
+            secret_key,
+        }
+    }
+}
+
+/// Returns None if the content type is too long.
+pub fn sign(key: &SigningKey, content_type: &str, body: &[u8]) -> Option<Vec<u8>> {
+    if content_type.len() > MAX_CONTENT_TYPE_LENGTH {
+        return None;
+    }
+    let signature = crypto::ed25519::signature(
+        &signed_data(&key.public_key, content_type, body),
+        &key.secret_key,
+    );
+    let mut envelope = MAGIC.to_vec();
+    envelope.push(VERSION);
+    envelope.extend_from_slice(&key.public_key);
+    envelope.extend_from_slice(&signature);
+    envelope.push(content_type.len() as u8);
+    envelope.extend_from_slice(content_type.as_bytes());
+    envelope.extend_from_slice(body);
+    Some(envelope)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Verification {
+    pub signer: Vec<u8>,
+    pub content_type: String,
+    pub body: Vec<u8>,
+    /// False if the signature doesn't match. The other fields are then
+    /// whatever the envelope claims.
+    pub authentic: bool,
+}
+
+/// Returns None if the payload isn't a signed envelope.
+pub fn verify(payload: &[u8]) -> Option<Verification> {
+    if payload.len() < HEADER_LENGTH
+        || !payload.starts_with(MAGIC)
+        || payload[MAGIC.len()] != VERSION
+    {
+        return None;
+    }
+    let signer = &payload[MAGIC.len() + 1..SIGNATURE_OFFSET];
+    let signature = &payload[SIGNATURE_OFFSET..SIGNATURE_OFFSET + SIGNATURE_LENGTH];
+    let content_type_end = HEADER_LENGTH + payload[HEADER_LENGTH - 1] as usize;
+    if payload.len() < content_type_end {
+        return None;
+    }
+    let content_type = std::str::from_utf8(&payload[HEADER_LENGTH..content_type_end]).ok()?;
+    let body = &payload[content_type_end..];
+    Some(Verification {
+        signer: signer.to_vec(),
+        content_type: content_type.to_owned(),
+        body: body.to_vec(),
+        authentic: identity::verify(signer, &signed_data(signer, content_type, body), signature),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        let (secret_key, public_key) = crypto::ed25519::keypair(&[seed; 32]);
+        SigningKey {
+            public_key,
+            secret_key,
+        }
+    }
+
+    #[test]
+    fn signed_envelopes_verify() {
+        let envelope = sign(&key(1), "text/plain", b"hello").unwrap();
+        let verification = verify(&envelope).unwrap();
+        assert!(verification.authentic);
+        assert_eq!(verification.signer, key(1).public_key);
+        assert_eq!(verification.content_type, "text/plain");
+        assert_eq!(verification.body, b"hello");
+    }
+
+    #[test]
+    fn another_signer_is_rejected() {
+        let mut envelope = sign(&key(1), "text/plain", b"hello").unwrap();
+        envelope[MAGIC.len() + 1..SIGNATURE_OFFSET].copy_from_slice(&key(2).public_key);
+        let verification = verify(&envelope).unwrap();
+        assert!(!verification.authentic);
+        assert_eq!(verification.signer, key(2).public_key);
+    }
+
+    #[test]
+    fn changed_content_is_rejected() {
+        let envelope = sign(&key(1), "text/plain", b"hello").unwrap();
+        let mut content_type = envelope.clone();
+        content_type[HEADER_LENGTH] = b'T';
+        let verification = verify(&content_type).unwrap();
+        assert!(!verification.authentic);
+        assert_eq!(verification.content_type, "Text/plain");
+        // Moving bytes between the content type and the body is caught too.
+        let mut boundary = envelope.clone();
+        boundary[HEADER_LENGTH - 1] += 1;
+        let verification = verify(&boundary).unwrap();
+        assert!(!verification.authentic);
+        assert_eq!(verification.content_type, "text/plainh");
+        let mut body = envelope;
+        *body.last_mut().unwrap() ^= 1;
+        assert!(!verify(&body).unwrap().authentic);
+    }
+
+    #[test]
+    fn other_payloads_are_not_envelopes() {
+        let envelope = sign(&key(1), "text/plain", b"").unwrap();
+        assert!(verify(b"hello").is_none());
+        assert!(verify(&envelope[..envelope.len() - 1]).is_none());
+        let mut version = envelope;
+        version[MAGIC.len()] += 1;
+        assert!(verify(&version).is_none());
+        assert!(sign(&key(1), &"a".repeat(256), b"").is_none());
+    }
+}
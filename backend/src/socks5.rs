@@ -0,0 +1,200 @@
+use async_std::net::TcpStream;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Turns `socks5://host:port` into `host:port`.
+pub fn parse_proxy_url(url: &str) -> Option<String> {
+    let address = url
+        .strip_prefix("socks5://")
+        .or_else(|| url.strip_prefix("socks5h://"))?;
+    split_address(address)?;
+    Some(address.to_owned())
+}
+
+fn split_address(address: &str) -> Option<(&str, u16)> {
+    let colon = address.rfind(':')?;
+    let host = address[..colon]
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    let port = address[colon + 1..].parse().ok()?;
+    Some((host, port))
+}
+
+fn reply_error(reply: u8) -> Error {
+    let reason = match reply {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    };
+    Error::new(
+        ErrorKind::ConnectionRefused,
+        format!("SOCKS5 proxy failed to connect: {}", reason),
+    )
+}
+
+/// Reads the proxy's reply to a connect request, up to where the tunnel
+/// starts.
+async fn read_reply<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<()> {
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Proxy doesn't speak SOCKS5",
+        ));
+    }
+    if reply[1] != 0 {
+        return Err(reply_error(reply[1]));
+    }
+    let bound_address_length = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => {
+            let mut length = [0u8; 1];
+            stream.read_exact(&mut length).await?;
+            length[0] as usize
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "SOCKS5 proxy replied with an unknown address type",
+            ))
+        }
+    };
+    let mut bound_address = vec![0u8; bound_address_length + 2];
+    stream.read_exact(&mut bound_address).await?;
+    Ok(())
+}
+
+/// Opens a tunnel to `address` through the proxy. Host names are resolved by
+/// the proxy, so names only it can resolve, like onion services, work.
+pub async fn connect(proxy: &str, address: &str) -> std::io::Result<TcpStream> {
+    let (host, port) = match split_address(address) {
+        Some(parts) => parts,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a valid address", address),
+            ))
+        }
+    };
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.len() <= 255 => {
+            request.push(DOMAIN_NAME);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+        Err(_) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is too long for SOCKS5", host),
+            ))
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting != [VERSION, NO_AUTHENTICATION] {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "SOCKS5 proxy requires authentication",
+        ));
+    }
+
+    stream.write_all(&request).await?;
+    read_reply(&mut stream).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+
+    /// Returns the outcome and how much of `reply` was read.
+    fn read(reply: &[u8]) -> (std::io::Result<()>, u64) {
+        let mut stream = Cursor::new(reply.to_vec());
+        let result = block_on(read_reply(&mut stream));
+        (result, stream.position())
+    }
+
+    #[test]
+    fn bound_addresses_are_skipped() {
+        for reply in &[
+            &[5, 0, 0, IPV4, 127, 0, 0, 1, 0x04, 0xd2][..],
+            &[
+                5, 0, 0, IPV6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x04, 0xd2,
+            ],
+            &[
+                5,
+                0,
+                0,
+                DOMAIN_NAME,
+                5,
+                b'p',
+                b'r',
+                b'o',
+                b'x',
+                b'y',
+                0x04,
+                0xd2,
+            ],
+        ] {
+            let mut bytes = reply.to_vec();
+            bytes.extend_from_slice(b"tunnel");
+            let (result, position) = read(&bytes);
+            assert!(result.is_ok());
+            assert_eq!(position, reply.len() as u64);
+        }
+    }
+
+    #[test]
+    fn errors_are_reported() {
+        for (code, reason) in &[
+            (1, "general failure"),
+            (5, "connection refused"),
+            (9, "unknown error"),
+        ] {
+            let error = read(&[5, *code, 0, IPV4, 0, 0, 0, 0, 0, 0]).0.unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+            assert!(error.to_string().ends_with(reason));
+        }
+        for reply in &[&[4, 0, 0, IPV4][..], &[5, 0, 0, 2]] {
+            assert_eq!(read(reply).0.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        assert_eq!(
+            read(&[5, 0, 0, IPV6, 0, 0]).0.unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -1,14 +1,26 @@
+use crate::announcer::Announcer;
 use crate::connect::{connect, reverse_connect};
 use crate::die_on_error::die_on_error;
-use crate::inventory;
+use crate::envelope;
+use crate::inbound_policy::{parse_ranges, InboundPolicy, RejectionCounts};
+use crate::inventory::{self, Insertion};
+use crate::learned_peers::LearnedPeer;
 use crate::log;
+use crate::message_hash::message_hash;
 use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::peer_manager::PeerManager;
+use crate::peers::Peer;
+use crate::protocol::SessionOptions;
+use crate::ratchet;
+use crate::signed_envelope::{self, Verification};
+use crate::socks5::parse_proxy_url;
 use async_std::sync::RwLock;
 use async_std::{io, task};
 use futures::executor::LocalSpawner;
 use futures::task::LocalSpawn;
 use r2d2_sqlite::SqliteConnectionManager;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::process::exit;
 use std::rc::Rc;
@@ -22,10 +34,22 @@ enum Operation {
     Submit {
         payload: Vec<u8>,
         expiration_time: i64,
+        /// When given, the payload is encrypted to these recipient public
+        /// keys first.
+        #[serde(default)]
+        recipients: Option<Vec<Vec<u8>>>,
+        /// When given, the payload is wrapped in an envelope with this
+        /// content type and signed with this node's signing key, before any
+        /// encryption.
+        #[serde(default)]
+        signed_content_type: Option<String>,
         operation_id: String,
     },
     Query {
         hash: Vec<u8>,
+        /// Checks whether the payload is a signed envelope.
+        #[serde(default)]
+        verify: bool,
         operation_id: String,
     },
     CancelSubmitOperation {
@@ -33,20 +57,102 @@ enum Operation {
     },
     EstablishConnection {
         address: String,
+        /// Overrides `--proxy`, as `socks5://host:port`.
+        #[serde(default)]
+        proxy: Option<String>,
         operation_id: String,
     },
     EstablishReverseConnection {
         address: String,
+        /// Overrides `--proxy`, as `socks5://host:port`.
+        #[serde(default)]
+        proxy: Option<String>,
+        operation_id: String,
+    },
+    AddPeer {
+        address: String,
+        /// Hands the peer out to other nodes through peer exchange.
+        #[serde(default)]
+        shared: bool,
+        operation_id: String,
+    },
+    RemovePeer {
+        address: String,
+        operation_id: String,
+    },
+    ListPeers {
+        operation_id: String,
+    },
+    ListLearnedPeers {
+        operation_id: String,
+    },
+    /// Omitted fields are left unchanged.
+    SetInboundPolicy {
+        #[serde(default)]
+        allow: Option<Vec<String>>,
+        #[serde(default)]
+        deny: Option<Vec<String>>,
+        #[serde(default)]
+        max_inbound_connections: Option<usize>,
+        #[serde(default)]
+        max_connections_per_address: Option<usize>,
+        operation_id: String,
+    },
+    GetInboundPolicy {
+        operation_id: String,
+    },
+    GenerateRecipientKey {
+        operation_id: String,
+    },
+    RemoveRecipientKey {
+        public_key: Vec<u8>,
+        operation_id: String,
+    },
+    ListRecipientKeys {
+        operation_id: String,
+    },
+    /// Asks for the public key that signed envelopes are verified with.
+    GetSigningKey {
+        operation_id: String,
+    },
+    /// Starts a forward-secret session with the holder of a recipient key.
+    /// Ciphertexts are submitted and queried like any other payload.
+    StartRatchetSession {
+        recipient: Vec<u8>,
+        operation_id: String,
+    },
+    /// Starts a session from a prekey message, the kind sent until the other
+    /// side first replies.
+    AcceptRatchetSession {
+        ciphertext: Vec<u8>,
+        operation_id: String,
+    },
+    RatchetEncrypt {
+        session_id: Vec<u8>,
+        plaintext: Vec<u8>,
+        operation_id: String,
+    },
+    RatchetDecrypt {
+        session_id: Vec<u8>,
+        ciphertext: Vec<u8>,
+        operation_id: String,
+    },
+    RemoveRatchetSession {
+        session_id: Vec<u8>,
         operation_id: String,
     },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Message<'a> {
     Inventory(Vec<Vec<u8>>),
     Message {
         in_reply_to: &'a str,
         message: Option<inventory::Message>,
+        /// Only present if verification was requested and the payload is a
+        /// signed envelope.
+        verification: Option<Verification>,
     },
     ProofOfWorkCancelled {
         in_reply_to: &'a str,
@@ -54,18 +160,134 @@ pub enum Message<'a> {
     ProofOfWorkCompleted {
         in_reply_to: &'a str,
     },
+    /// Sent instead of `ProofOfWorkCompleted` when the eviction policy ranks
+    /// the message below everything in the full inventory.
+    InventoryFull {
+        in_reply_to: &'a str,
+    },
     ConnectionEstablishmentFailure {
         in_reply_to: &'a str,
     },
     ReconcileFailure {
         in_reply_to: &'a str,
     },
+    /// Sent instead of connecting when the proxy URL can't be parsed.
+    InvalidProxy {
+        in_reply_to: &'a str,
+    },
     ServerListenAddress {
         address: &'a str,
     },
     ClientListenAddress {
         address: &'a str,
     },
+    PeerAdded {
+        in_reply_to: &'a str,
+    },
+    PeerRemoved {
+        in_reply_to: &'a str,
+    },
+    Peers {
+        in_reply_to: &'a str,
+        peers: Vec<Peer>,
+    },
+    LearnedPeers {
+        in_reply_to: &'a str,
+        peers: Vec<LearnedPeer>,
+    },
+    PeerLearned {
+        address: &'a str,
+    },
+    PeerDiscovered {
+        address: &'a str,
+    },
+    InboundPolicySet {
+        in_reply_to: &'a str,
+    },
+    /// Sent instead of `InboundPolicySet` when the policy is rejected. The
+    /// current policy stays in effect.
+    InvalidInboundPolicy {
+        in_reply_to: &'a str,
+        reason: &'a str,
+    },
+    InboundPolicy {
+        in_reply_to: &'a str,
+        allow: Vec<String>,
+        deny: Vec<String>,
+        max_inbound_connections: usize,
+        max_connections_per_address: usize,
+        inbound_connections: usize,
+        rejected: RejectionCounts,
+    },
+    RecipientKeyGenerated {
+        in_reply_to: &'a str,
+        public_key: Vec<u8>,
+    },
+    RecipientKeyRemoved {
+        in_reply_to: &'a str,
+    },
+    RecipientKeys {
+        in_reply_to: &'a str,
+        public_keys: Vec<Vec<u8>>,
+    },
+    /// Sent instead of submitting or starting a ratchet session when a
+    /// recipient key is malformed.
+    InvalidRecipients {
+        in_reply_to: &'a str,
+    },
+    /// Sent instead of submitting when the signed content type is longer
+    /// than 255 bytes.
+    InvalidContentType {
+        in_reply_to: &'a str,
+    },
+    SigningKey {
+        in_reply_to: &'a str,
+        public_key: Vec<u8>,
+    },
+    /// Sent instead of submitting when the payload, once signed and
+    /// encrypted, is larger than peers accept.
+    PayloadTooLarge {
+        in_reply_to: &'a str,
+    },
+    MessageForYou {
+        hash: Vec<u8>,
+        recipient: Vec<u8>,
+        plaintext: Vec<u8>,
+        /// Present if the plaintext is a signed envelope.
+        verification: Option<Verification>,
+        expiration_time: i64,
+    },
+    /// Hashes of messages removed from the inventory because they expired.
+    MessagesExpired {
+        hashes: Vec<Vec<u8>>,
+    },
+    RatchetSessionStarted {
+        in_reply_to: &'a str,
+        session_id: Vec<u8>,
+    },
+    RatchetSessionAccepted {
+        in_reply_to: &'a str,
+        session_id: Vec<u8>,
+        plaintext: Vec<u8>,
+    },
+    RatchetEncrypted {
+        in_reply_to: &'a str,
+        ciphertext: Vec<u8>,
+    },
+    RatchetDecrypted {
+        in_reply_to: &'a str,
+        plaintext: Vec<u8>,
+    },
+    RatchetDecryptionFailure {
+        in_reply_to: &'a str,
+    },
+    /// Sent when encrypting in a session that doesn't exist.
+    RatchetSessionNotFound {
+        in_reply_to: &'a str,
+    },
+    RatchetSessionRemoved {
+        in_reply_to: &'a str,
+    },
 }
 
 pub fn format_struct<T: Serialize>(value: &T) -> String {
@@ -74,24 +296,36 @@ pub fn format_struct<T: Serialize>(value: &T) -> String {
 
 pub async fn communicate(
     reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+    announcer: std::rc::Rc<RwLock<Announcer>>,
     connection: std::sync::Arc<r2d2::Pool<SqliteConnectionManager>>,
     spawner: LocalSpawner,
+    options: std::rc::Rc<SessionOptions>,
+    peer_manager: PeerManager,
+    inbound_policy: Rc<RefCell<InboundPolicy>>,
 ) {
+    die_on_error(
+        spawner.spawn_local_obj(
+            Box::new(envelope::watch(
+                connection.clone(),
+                options.inventory.clone(),
+                reconciliation_intent.clone(),
+            ))
+            .into(),
+        ),
+    );
     let atomic_cancel_flags: Rc<RwLock<HashMap<String, Arc<AtomicBool>>>> =
         Rc::new(RwLock::new(HashMap::new()));
+    let ratchet_locks = ratchet::SessionLocks::default();
     {
-        let connection = connection.clone();
+        let inventory = options.inventory.clone();
         let reconciliation_intent = reconciliation_intent.clone();
         die_on_error(
             spawner.spawn_local_obj(
                 Box::new(async move {
                     let handle = reconciliation_intent.write().await.get_handle();
                     loop {
-                        let channel = inventory::hashes(connection.clone());
-                        let mut hashes = Vec::new();
-                        while let Some(hash) = channel.recv().await {
-                            hashes.push(hash);
-                        }
+                        let inventory = inventory.clone();
+                        let hashes = task::spawn(async move { inventory.hashes() }).await;
                         log::ipc(format_struct(&Message::Inventory(hashes)));
                         let event = reconciliation_intent.read().await.get_event(handle);
                         event.wait().await;
@@ -131,14 +365,59 @@ pub async fn communicate(
                     Operation::Submit {
                         payload,
                         expiration_time,
+                        recipients,
+                        signed_content_type,
                         operation_id,
                     } => {
+                        let payload = match signed_content_type {
+                            Some(content_type) => {
+                                match signed_envelope::sign(
+                                    &options.signing_key,
+                                    &content_type,
+                                    &payload,
+                                ) {
+                                    Some(payload) => payload,
+                                    None => {
+                                        log::warning(
+                                            "Message not submitted, content type is too long",
+                                        );
+                                        log::ipc(format_struct(&Message::InvalidContentType {
+                                            in_reply_to: &operation_id,
+                                        }));
+                                        continue;
+                                    }
+                                }
+                            }
+                            None => payload,
+                        };
+                        let payload = match recipients {
+                            Some(recipients) => match envelope::seal(&payload, &recipients) {
+                                Some(payload) => payload,
+                                None => {
+                                    log::warning("Message not submitted, recipients are invalid");
+                                    log::ipc(format_struct(&Message::InvalidRecipients {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                    continue;
+                                }
+                            },
+                            None => payload,
+                        };
+                        if payload.len() > options.limits.max_payload_size {
+                            log::warning("Message not submitted, payload is too large");
+                            log::ipc(format_struct(&Message::PayloadTooLarge {
+                                in_reply_to: &operation_id,
+                            }));
+                            continue;
+                        }
                         log::notice(
                             "A task has been spawned to calculate the proof of work. Hang tight.",
                         );
                         let atomic_cancel_flags = atomic_cancel_flags.clone();
                         let reconciliation_intent = reconciliation_intent.clone();
-                        let connection = connection.clone();
+                        let announcer = announcer.clone();
+                        let inventory = options.inventory.clone();
+                        let proof_of_work_key = options.proof_of_work_key;
                         die_on_error(
                             spawner.spawn_local_obj(
                                 Box::new(async move {
@@ -167,6 +446,7 @@ pub async fn communicate(
                                             }
                                         },
                                         cancelled2,
+                                        proof_of_work_key.as_ref().map(|key| &key[..]),
                                     )
                                     .await;
                                     let nonce = match nonce {
@@ -185,29 +465,52 @@ pub async fn communicate(
                                             return;
                                         }
                                     };
-                                    task::spawn(async move {
-                                        inventory::insert(connection, &payload, nonce, expiration_time);
+                                    let hash = message_hash(&payload, expiration_time).to_vec();
+                                    let insertion = task::spawn(async move {
+                                        inventory.insert(&payload, nonce, expiration_time)
                                     }).await;
-                                    reconciliation_intent.read().await.broadcast();
-                                    log::ipc(format_struct(&Message::ProofOfWorkCompleted {
-                                        in_reply_to: &operation_id,
-                                    }));
                                     atomic_cancel_flags
                                         .write()
                                         .await
                                         .remove(&operation_id);
+                                    if insertion == Insertion::Full {
+                                        log::ipc(format_struct(&Message::InventoryFull {
+                                            in_reply_to: &operation_id,
+                                        }));
+                                        log::warning("Message rejected because the inventory is full");
+                                        return;
+                                    }
+                                    if insertion == Insertion::Inserted {
+                                        reconciliation_intent.read().await.broadcast();
+                                        announcer.read().await.announce(&[hash]);
+                                    }
+                                    log::ipc(format_struct(&Message::ProofOfWorkCompleted {
+                                        in_reply_to: &operation_id,
+                                    }));
                                     log::notice("Message submitted successfully");
                                 })
                                 .into(),
                             ),
                         );
                     }
-                    Operation::Query { hash, operation_id } => {
-                        let connection = connection.clone();
+                    Operation::Query {
+                        hash,
+                        verify,
+                        operation_id,
+                    } => {
+                        let inventory = options.inventory.clone();
                         task::spawn(async move {
+                            let message = inventory.retrieve(&hash);
+                            let verification = match &message {
+                                Some(message) if verify => {
+                                    signed_envelope::verify(&message.payload)
+                                }
+                                _ => None,
+                            };
                             log::ipc(format_struct(&Message::Message {
                                 in_reply_to: &operation_id,
-                                message: inventory::retrieve(connection, &hash),
+                                message,
+                                verification,
                             }));
                         });
                     }
@@ -227,17 +530,36 @@ pub async fn communicate(
                     }
                     Operation::EstablishConnection {
                         address,
+                        proxy,
                         operation_id,
                     } => {
+                        let proxy = match proxy {
+                            Some(proxy) => match parse_proxy_url(&proxy) {
+                                Some(proxy) => Some(proxy),
+                                None => {
+                                    log::warning(
+                                        "Connection not established, proxy URL is invalid",
+                                    );
+                                    log::ipc(format_struct(&Message::InvalidProxy {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                    continue;
+                                }
+                            },
+                            None => options.proxy.clone(),
+                        };
                         let operation_id1 = std::rc::Rc::new(operation_id);
                         let operation_id2 = operation_id1.clone();
                         let socket_address1 = std::rc::Rc::new(address.clone());
                         let socket_address2 = socket_address1.clone();
                         connect(
                             address,
+                            proxy,
                             connection.clone(),
                             spawner.clone(),
                             reconciliation_intent.clone(),
+                            announcer.clone(),
+                            options.clone(),
                             move |error| {
                                 log::warning(format!(
                                     "Can't connect to {} due to error {:?}",
@@ -247,30 +569,51 @@ pub async fn communicate(
                                     in_reply_to: &operation_id1,
                                 }));
                             },
-                            move |error| {
-                                log::warning(format!(
-                                    "Error occurred while reconciling with {} due to error {:?}",
-                                    socket_address2, error
-                                ));
-                                log::ipc(format_struct(&Message::ReconcileFailure {
-                                    in_reply_to: &operation_id2,
-                                }));
+                            move |result| {
+                                if let Err(error) = result {
+                                    log::warning(format!(
+                                        "Error occurred while reconciling with {} due to error {:?}",
+                                        socket_address2, error
+                                    ));
+                                    log::ipc(format_struct(&Message::ReconcileFailure {
+                                        in_reply_to: &operation_id2,
+                                    }));
+                                }
                             },
                         );
                     }
                     Operation::EstablishReverseConnection {
                         address,
+                        proxy,
                         operation_id,
                     } => {
+                        let proxy = match proxy {
+                            Some(proxy) => match parse_proxy_url(&proxy) {
+                                Some(proxy) => Some(proxy),
+                                None => {
+                                    log::warning(
+                                        "Connection not established, proxy URL is invalid",
+                                    );
+                                    log::ipc(format_struct(&Message::InvalidProxy {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                    continue;
+                                }
+                            },
+                            None => options.proxy.clone(),
+                        };
                         let operation_id1 = std::rc::Rc::new(operation_id);
                         let operation_id2 = operation_id1.clone();
                         let socket_address1 = std::rc::Rc::new(address.clone());
                         let socket_address2 = socket_address1.clone();
                         reverse_connect(
                             address,
+                            proxy,
                             connection.clone(),
                             spawner.clone(),
                             reconciliation_intent.clone(),
+                            announcer.clone(),
+                            options.clone(),
                             move |error| {
                                 log::warning(format!(
                                     "Can't connect to {} due to error {:?}",
@@ -291,6 +634,302 @@ pub async fn communicate(
                             },
                         );
                     }
+                    Operation::AddPeer {
+                        address,
+                        shared,
+                        operation_id,
+                    } => {
+                        let peer_manager = peer_manager.clone();
+                        die_on_error(
+                            spawner.spawn_local_obj(
+                                Box::new(async move {
+                                    peer_manager.add(address, shared).await;
+                                    log::ipc(format_struct(&Message::PeerAdded {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                })
+                                .into(),
+                            ),
+                        );
+                    }
+                    Operation::RemovePeer {
+                        address,
+                        operation_id,
+                    } => {
+                        let peer_manager = peer_manager.clone();
+                        die_on_error(
+                            spawner.spawn_local_obj(
+                                Box::new(async move {
+                                    peer_manager.remove(address).await;
+                                    log::ipc(format_struct(&Message::PeerRemoved {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                })
+                                .into(),
+                            ),
+                        );
+                    }
+                    Operation::ListPeers { operation_id } => {
+                        let peer_manager = peer_manager.clone();
+                        die_on_error(
+                            spawner.spawn_local_obj(
+                                Box::new(async move {
+                                    log::ipc(format_struct(&Message::Peers {
+                                        in_reply_to: &operation_id,
+                                        peers: peer_manager.list().await,
+                                    }));
+                                })
+                                .into(),
+                            ),
+                        );
+                    }
+                    Operation::ListLearnedPeers { operation_id } => {
+                        let peer_manager = peer_manager.clone();
+                        die_on_error(
+                            spawner.spawn_local_obj(
+                                Box::new(async move {
+                                    log::ipc(format_struct(&Message::LearnedPeers {
+                                        in_reply_to: &operation_id,
+                                        peers: peer_manager.list_learned().await,
+                                    }));
+                                })
+                                .into(),
+                            ),
+                        );
+                    }
+                    Operation::SetInboundPolicy {
+                        allow,
+                        deny,
+                        max_inbound_connections,
+                        max_connections_per_address,
+                        operation_id,
+                    } => {
+                        let parse = |ranges: Option<Vec<String>>| match ranges {
+                            Some(ranges) => parse_ranges(ranges.iter().map(String::as_str))
+                                .map(Some)
+                                .ok_or("an address range is invalid"),
+                            None => Ok(None),
+                        };
+                        let parsed = match (parse(allow), parse(deny)) {
+                            (Ok(allow), Ok(deny))
+                                if max_inbound_connections != Some(0)
+                                    && max_connections_per_address != Some(0) =>
+                            {
+                                Ok((allow, deny))
+                            }
+                            (Err(reason), _) | (_, Err(reason)) => Err(reason),
+                            _ => Err("connection limits must be positive"),
+                        };
+                        let (allow, deny) = match parsed {
+                            Ok(ranges) => ranges,
+                            Err(reason) => {
+                                log::warning(format!(
+                                    "Inbound connection policy unchanged, {}",
+                                    reason
+                                ));
+                                log::ipc(format_struct(&Message::InvalidInboundPolicy {
+                                    in_reply_to: &operation_id,
+                                    reason,
+                                }));
+                                continue;
+                            }
+                        };
+                        let mut policy = inbound_policy.borrow_mut();
+                        if let Some(allow) = allow {
+                            policy.allow = allow;
+                        }
+                        if let Some(deny) = deny {
+                            policy.deny = deny;
+                        }
+                        if let Some(max_inbound_connections) = max_inbound_connections {
+                            policy.max_connections = max_inbound_connections;
+                        }
+                        if let Some(max_connections_per_address) = max_connections_per_address {
+                            policy.max_connections_per_address = max_connections_per_address;
+                        }
+                        log::notice("Inbound connection policy updated");
+                        log::ipc(format_struct(&Message::InboundPolicySet {
+                            in_reply_to: &operation_id,
+                        }));
+                    }
+                    Operation::GetSigningKey { operation_id } => {
+                        log::ipc(format_struct(&Message::SigningKey {
+                            in_reply_to: &operation_id,
+                            public_key: options.signing_key.public_key.to_vec(),
+                        }));
+                    }
+                    Operation::GenerateRecipientKey { operation_id } => {
+                        let connection = connection.clone();
+                        task::spawn(async move {
+                            log::ipc(format_struct(&Message::RecipientKeyGenerated {
+                                in_reply_to: &operation_id,
+                                public_key: envelope::generate(connection),
+                            }));
+                        });
+                    }
+                    Operation::RemoveRecipientKey {
+                        public_key,
+                        operation_id,
+                    } => {
+                        let connection = connection.clone();
+                        task::spawn(async move {
+                            envelope::remove(connection, &public_key);
+                            log::ipc(format_struct(&Message::RecipientKeyRemoved {
+                                in_reply_to: &operation_id,
+                            }));
+                        });
+                    }
+                    Operation::ListRecipientKeys { operation_id } => {
+                        let connection = connection.clone();
+                        task::spawn(async move {
+                            log::ipc(format_struct(&Message::RecipientKeys {
+                                in_reply_to: &operation_id,
+                                public_keys: envelope::list(connection)
+                                    .into_iter()
+                                    .map(|key| key.public_key)
+                                    .collect(),
+                            }));
+                        });
+                    }
+                    Operation::StartRatchetSession {
+                        recipient,
+                        operation_id,
+                    } => {
+                        let connection = connection.clone();
+                        task::spawn(async move {
+                            match ratchet::start(connection, &recipient) {
+                                Some(session_id) => {
+                                    log::ipc(format_struct(&Message::RatchetSessionStarted {
+                                        in_reply_to: &operation_id,
+                                        session_id,
+                                    }));
+                                }
+                                None => {
+                                    log::warning(
+                                        "Ratchet session not started, recipient is invalid",
+                                    );
+                                    log::ipc(format_struct(&Message::InvalidRecipients {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                }
+                            }
+                        });
+                    }
+                    Operation::AcceptRatchetSession {
+                        ciphertext,
+                        operation_id,
+                    } => {
+                        let connection = connection.clone();
+                        let ratchet_locks = ratchet_locks.clone();
+                        task::spawn(async move {
+                            // New sessions are accepted one at a time under the
+                            // empty ID. Check again once locked, in case the
+                            // message's session was accepted in the meantime.
+                            let accepted = loop {
+                                let id = ratchet::session_of(connection.clone(), &ciphertext)
+                                    .unwrap_or_default();
+                                let lock = ratchet_locks.get(&id);
+                                let _guard = lock.lock().await;
+                                if ratchet::session_of(connection.clone(), &ciphertext)
+                                    .unwrap_or_default()
+                                    == id
+                                {
+                                    break ratchet::accept(connection, &ciphertext);
+                                }
+                            };
+                            match accepted {
+                                Some((session_id, plaintext)) => {
+                                    log::ipc(format_struct(&Message::RatchetSessionAccepted {
+                                        in_reply_to: &operation_id,
+                                        session_id,
+                                        plaintext,
+                                    }));
+                                }
+                                None => {
+                                    log::ipc(format_struct(&Message::RatchetDecryptionFailure {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                }
+                            }
+                        });
+                    }
+                    Operation::RatchetEncrypt {
+                        session_id,
+                        plaintext,
+                        operation_id,
+                    } => {
+                        let connection = connection.clone();
+                        let lock = ratchet_locks.get(&session_id);
+                        task::spawn(async move {
+                            let _guard = lock.lock().await;
+                            match ratchet::seal(connection, &session_id, &plaintext) {
+                                Some(ciphertext) => {
+                                    log::ipc(format_struct(&Message::RatchetEncrypted {
+                                        in_reply_to: &operation_id,
+                                        ciphertext,
+                                    }));
+                                }
+                                None => {
+                                    log::warning("Can't encrypt, ratchet session doesn't exist");
+                                    log::ipc(format_struct(&Message::RatchetSessionNotFound {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                }
+                            }
+                        });
+                    }
+                    Operation::RatchetDecrypt {
+                        session_id,
+                        ciphertext,
+                        operation_id,
+                    } => {
+                        let connection = connection.clone();
+                        let lock = ratchet_locks.get(&session_id);
+                        task::spawn(async move {
+                            let _guard = lock.lock().await;
+                            match ratchet::open(connection, &session_id, &ciphertext) {
+                                Some(plaintext) => {
+                                    log::ipc(format_struct(&Message::RatchetDecrypted {
+                                        in_reply_to: &operation_id,
+                                        plaintext,
+                                    }));
+                                }
+                                None => {
+                                    log::ipc(format_struct(&Message::RatchetDecryptionFailure {
+                                        in_reply_to: &operation_id,
+                                    }));
+                                }
+                            }
+                        });
+                    }
+                    Operation::RemoveRatchetSession {
+                        session_id,
+                        operation_id,
+                    } => {
+                        let connection = connection.clone();
+                        let ratchet_locks = ratchet_locks.clone();
+                        task::spawn(async move {
+                            let lock = ratchet_locks.get(&session_id);
+                            let _guard = lock.lock().await;
+                            ratchet::remove(connection, &session_id);
+                            ratchet_locks.forget(&session_id);
+                            log::ipc(format_struct(&Message::RatchetSessionRemoved {
+                                in_reply_to: &operation_id,
+                            }));
+                        });
+                    }
+                    Operation::GetInboundPolicy { operation_id } => {
+                        let policy = inbound_policy.borrow();
+                        log::ipc(format_struct(&Message::InboundPolicy {
+                            in_reply_to: &operation_id,
+                            allow: policy.allow.iter().map(|range| range.to_string()).collect(),
+                            deny: policy.deny.iter().map(|range| range.to_string()).collect(),
+                            max_inbound_connections: policy.max_connections,
+                            max_connections_per_address: policy.max_connections_per_address,
+                            inbound_connections: policy.connection_count(),
+                            rejected: policy.rejected(),
+                        }));
+                    }
                 }
             }
             Err(error) => {
@@ -0,0 +1,47 @@
+use crate::inventory::Inventory;
+use crate::log;
+use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::stdio_ipc::{format_struct, Message};
+use async_std::sync::RwLock;
+use async_std::task;
+use chrono::Utc;
+use std::time::Duration;
+
+/// Longest time between sweeps.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Deletes expired messages when the earliest one expires, or every
+/// `SWEEP_INTERVAL` at the latest. Insertions wake the sweeper, since they
+/// may expire sooner than anything else.
+pub async fn run(
+    inventory: Inventory,
+    reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+) {
+    let handle = reconciliation_intent.write().await.get_handle();
+    let event = reconciliation_intent.read().await.get_event(handle);
+    loop {
+        let inventory = inventory.clone();
+        let (hashes, next_expiration_time) =
+            task::spawn(
+                async move { (inventory.purge_expired(), inventory.next_expiration_time()) },
+            )
+            .await;
+        if !hashes.is_empty() {
+            log::notice(format!("Purged {} expired messages", hashes.len()));
+            log::ipc(format_struct(&Message::MessagesExpired { hashes }));
+            reconciliation_intent
+                .read()
+                .await
+                .broadcast_to_others(handle);
+        }
+        let delay = match next_expiration_time {
+            Some(expiration_time) => {
+                Duration::from_secs((expiration_time - Utc::now().timestamp()).max(0) as u64)
+                    .min(SWEEP_INTERVAL)
+            }
+            None => SWEEP_INTERVAL,
+        };
+        let _ = async_std::future::timeout(delay, event.wait()).await;
+        event.reset();
+    }
+}